@@ -0,0 +1,140 @@
+// Sends many requests at once to a running server and reports throughput
+// and latency, e.g. with a server started from a copy of main.db:
+//
+//   cargo run --release --example load_test -- \
+//       http://127.0.0.1:9898/search 64 100 -H "Cookie: session=..."
+//
+// Arguments are the URL, how many clients send at the same time, how many
+// requests each of them sends one after the other, and headers to send
+// along. Every request uses a new connection, like a browser that's been
+// idle for a bit.
+
+use std::env;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Target {
+    address: String,
+    path: String,
+    headers: Vec<String>,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: load_test http://HOST:PORT/PATH [CLIENTS] [REQUESTS] [-H HEADER]...");
+    std::process::exit(2);
+}
+
+fn parse_args() -> (Target, usize, usize) {
+    let mut args = env::args().skip(1);
+    let mut positional = vec![];
+    let mut headers = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-H" => headers.push(args.next().unwrap_or_else(|| usage())),
+            _ => positional.push(arg),
+        }
+    }
+    let Some(url) = positional.first() else {
+        usage();
+    };
+    let Some(rest) = url.strip_prefix("http://") else {
+        usage();
+    };
+    let (address, path) = match rest.find('/') {
+        Some(i) => (rest[..i].to_string(), rest[i..].to_string()),
+        None => (rest.to_string(), "/".to_string()),
+    };
+    // At least one of each, or there's nothing to measure.
+    let number = |index: usize, default: usize| match positional.get(index) {
+        Some(n) => match n.parse::<usize>() {
+            Ok(0) | Err(_) => usage(),
+            Ok(n) => n,
+        },
+        None => default,
+    };
+    let target = Target {
+        address,
+        path,
+        headers,
+    };
+    (target, number(1, 32), number(2, 100))
+}
+
+// The status code, or None if there was no proper answer.
+fn send(target: &Target) -> Option<u16> {
+    let mut stream = TcpStream::connect(&target.address).ok()?;
+    stream
+        .set_read_timeout(Some(Duration::from_secs(30)))
+        .ok()?;
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+        target.path, target.address
+    );
+    for header in target.headers.iter() {
+        request += &format!("{}\r\n", header);
+    }
+    request += "\r\n";
+    stream.write_all(request.as_bytes()).ok()?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response).ok()?;
+    let response = String::from_utf8_lossy(&response);
+    response.split(' ').nth(1)?.parse().ok()
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Option<Duration> {
+    let last = sorted.len().checked_sub(1)?;
+    sorted.get(last * p / 100).copied()
+}
+
+fn main() {
+    let (target, clients, requests) = parse_args();
+    let target = &target;
+    let started = Instant::now();
+    let results = thread::scope(|scope| {
+        let clients = (0..clients)
+            .map(|_| {
+                scope.spawn(move || {
+                    (0..requests)
+                        .map(|_| {
+                            let sent = Instant::now();
+                            let status = send(target);
+                            (status, sent.elapsed())
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        clients
+            .into_iter()
+            .flat_map(|client| client.join().expect("A client to finish"))
+            .collect::<Vec<_>>()
+    });
+    let elapsed = started.elapsed();
+
+    let failed = results
+        .iter()
+        .filter(|(status, _)| !status.is_some_and(|s| s < 500))
+        .count();
+    let mut latencies = results.iter().map(|(_, l)| *l).collect::<Vec<_>>();
+    latencies.sort();
+    println!(
+        "{} requests from {} clients in {:.2?}, {} failed",
+        results.len(),
+        clients,
+        elapsed,
+        failed
+    );
+    println!(
+        "{:.0} requests/s",
+        results.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "latency p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, max {:.2?}",
+        percentile(&latencies, 50).unwrap_or_default(),
+        percentile(&latencies, 90).unwrap_or_default(),
+        percentile(&latencies, 99).unwrap_or_default(),
+        percentile(&latencies, 100).unwrap_or_default()
+    );
+}
@@ -0,0 +1,937 @@
+use crate::db;
+use crate::errors::{ApiError, AppError, Result};
+use crate::forms;
+use crate::fulltext;
+use crate::sharing::{self, Sharing};
+use crate::units::{self, UnitSystem};
+use crate::users::User;
+use crate::{
+    find_header, get_all_ingredients, get_con, get_filtered_recipes, get_recipe_by_id, get_recipes,
+    get_usize, not_found, query_value, query_values, recipe_id_by_name, resolve_ingredient_lines,
+    resolve_steps, Ingredient, IngredientLine, Recipe, SearchFilter, StepLine,
+};
+use rusqlite::{named_params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Method, Request, Response, ResponseBox};
+
+// An ingredient is referenced either by its id or by its name. Names that
+// don't exist yet are created, the same way the HTML form does it, and a
+// name is never taken for an id: "12" is an ingredient called 12.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IngredientRef {
+    Id(usize),
+    Name(String),
+}
+
+// Either just the ingredient, or the ingredient with the amount used, e.g.
+// `{"ingredient": "flour", "quantity": 200, "unit": "g"}`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IngredientInput {
+    Line {
+        ingredient: IngredientRef,
+        quantity: Option<f64>,
+        unit: Option<String>,
+        note: Option<String>,
+    },
+    Plain(IngredientRef),
+}
+
+// Either just the text, or the text with a timer and the ingredients used,
+// e.g. `{"text": "Bake.", "timer_seconds": 1200, "ingredients": ["dough"]}`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StepInput {
+    Step {
+        text: String,
+        #[serde(default)]
+        timer_seconds: Option<u32>,
+        #[serde(default)]
+        ingredients: Vec<IngredientRef>,
+    },
+    Text(String),
+}
+
+#[derive(Deserialize)]
+struct RecipeInput {
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    ingredients: Vec<IngredientInput>,
+    #[serde(default)]
+    steps: Vec<StepInput>,
+    #[serde(default)]
+    servings: Option<u32>,
+    #[serde(default)]
+    prep_minutes: Option<u32>,
+    #[serde(default)]
+    cook_minutes: Option<u32>,
+    #[serde(default)]
+    total_minutes: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct MergeInput {
+    into: usize,
+    from: Vec<usize>,
+}
+
+// Entry point for everything under /api/v1/.
+pub fn handle_api(request: &mut Request, user: &User) -> Result<ResponseBox> {
+    let url = request.url().to_string();
+    let path = url.split('?').next().unwrap_or_default();
+    let Some(path) = path.strip_prefix("/api/v1/") else {
+        return Err(not_found("Unknown API version"));
+    };
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<&str>>();
+    let method = request.method().clone();
+
+    match (method, segments.as_slice()) {
+        (Method::Get, ["recipes"]) => serve_json(200, &get_recipes(user)?),
+        (Method::Post, ["recipes"]) => {
+            let input: RecipeInput = read_json(request)?;
+            create_recipe(input, user)
+        }
+        (Method::Get, ["recipes", id]) => {
+            let recipe = recipe_by_id(id, user)?;
+            let servings = query_value(&url, "servings").and_then(|s| s.parse::<u32>().ok());
+            let system = query_value(&url, "units").and_then(|u| UnitSystem::parse(&u));
+            serve_json(200, &recipe.scaled(servings, system))
+        }
+        (Method::Put, ["recipes", id]) => {
+            let recipe = recipe_by_id(id, user)?;
+            if !recipe.sharing.can_edit(&*get_con()?, user)? {
+                return Err(AppError::Forbidden(
+                    "Not allowed to change this recipe".to_string(),
+                ));
+            }
+            let input: RecipeInput = read_json(request)?;
+            update_recipe(recipe, input)
+        }
+        (Method::Delete, ["recipes", id]) => {
+            let recipe = recipe_by_id(id, user)?;
+            if !recipe.sharing.can_manage(user) {
+                return Err(AppError::Forbidden(
+                    "Only the owner can delete this recipe".to_string(),
+                ));
+            }
+            recipe.delete()?;
+            Ok(Response::empty(204).boxed())
+        }
+        (Method::Get, ["ingredients"]) => serve_json(200, &get_all_ingredients()?),
+        (Method::Post, ["ingredients", "merge"]) => {
+            let input: MergeInput = read_json(request)?;
+            merge_ingredients(input, user)
+        }
+        (Method::Get, ["search"]) => {
+            if let Some(text) = query_value(&url, "q") {
+                return serve_json(200, &fulltext::search(&*get_con()?, &text, user)?);
+            }
+            let filter = SearchFilter {
+                ingredients: query_list(&url, "ingredients"),
+                required: query_list(&url, "required"),
+                excluded: query_list(&url, "excluded"),
+                max_missing: query_value(&url, "max_missing").and_then(|m| get_usize(&m)),
+            };
+            if filter.is_empty() {
+                return Err(AppError::Validation(
+                    "Missing q or ingredients parameter".to_string(),
+                ));
+            }
+            serve_json(200, &get_filtered_recipes(&*get_con()?, &filter, user)?)
+        }
+        (_, ["recipes"]) => method_not_allowed("GET, POST"),
+        (_, ["recipes", _]) => method_not_allowed("GET, PUT, DELETE"),
+        (_, ["ingredients"] | ["search"]) => method_not_allowed("GET"),
+        (_, ["ingredients", "merge"]) => method_not_allowed("POST"),
+        _ => Err(not_found("Not found")),
+    }
+}
+
+fn recipe_by_id(id: &str, user: &User) -> Result<Recipe> {
+    let recipe = match get_usize(id) {
+        Some(id) => get_recipe_by_id(id, user)?,
+        None => None,
+    };
+    recipe.ok_or_else(|| not_found("Recipe not found"))
+}
+
+fn create_recipe(input: RecipeInput, user: &User) -> Result<ResponseBox> {
+    let name = input.name.trim().to_string();
+    if name.is_empty() {
+        return Err(AppError::Validation("Name is required".to_string()));
+    }
+    if recipe_id_by_name(&*get_con()?, &name)?.is_some() {
+        return serve_error(409, "A recipe with this name already exists");
+    }
+    let all = get_all_ingredients()?;
+    let mut con = get_con()?;
+    let tx = db::write_transaction(&mut con)?;
+    let ingredients = resolve_ingredient_lines(&tx, ingredient_lines(input.ingredients, &all)?)?;
+    let created = Recipe {
+        id: 0,
+        name,
+        steps: resolve_steps(step_lines(input.steps, &all)?, &ingredients),
+        ingredients,
+        description: input.description,
+        servings: input.servings.filter(|s| *s > 0),
+        prep_minutes: input.prep_minutes,
+        cook_minutes: input.cook_minutes,
+        total_minutes: input.total_minutes,
+        sharing: Sharing::owned_by(user),
+    }
+    .insert(&tx)?;
+    tx.commit()?;
+    serve_json(201, &created)
+}
+
+fn update_recipe(mut recipe: Recipe, input: RecipeInput) -> Result<ResponseBox> {
+    let name = input.name.trim().to_string();
+    if name.is_empty() {
+        return Err(AppError::Validation("Name is required".to_string()));
+    }
+    if recipe_id_by_name(&*get_con()?, &name)?.is_some_and(|id| id != recipe.id) {
+        return serve_error(409, "A recipe with this name already exists");
+    }
+    let all = get_all_ingredients()?;
+    let mut con = get_con()?;
+    let tx = db::write_transaction(&mut con)?;
+    recipe.ingredients = resolve_ingredient_lines(&tx, ingredient_lines(input.ingredients, &all)?)?;
+    recipe.steps = resolve_steps(step_lines(input.steps, &all)?, &recipe.ingredients);
+    recipe.name = name;
+    recipe.description = input.description;
+    recipe.servings = input.servings.filter(|s| *s > 0);
+    recipe.prep_minutes = input.prep_minutes;
+    recipe.cook_minutes = input.cook_minutes;
+    recipe.total_minutes = input.total_minutes;
+    recipe.update(&tx)?;
+    tx.commit()?;
+    serve_json(200, &recipe)
+}
+
+// Points every recipe, pantry item and shopping list item that uses one of
+// the `from` ingredients at `into` instead and removes the merged
+// ingredients. Ingredients are shared by
+// all recipes, so that's only allowed if the user can change every recipe
+// that uses them.
+fn merge_ingredients(input: MergeInput, user: &User) -> Result<ResponseBox> {
+    let all = get_all_ingredients()?;
+    let Some(target) = all.into_iter().find(|i| i.id == input.into) else {
+        return Err(not_found("Ingredient not found"));
+    };
+    let mut con = get_con()?;
+    let tx = db::write_transaction(&mut con)?;
+    let from = input
+        .from
+        .iter()
+        .filter(|&&id| id != target.id)
+        .map(|id| id.to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+    let locked: usize = tx.query_row(
+        &format!(
+            "SELECT count(*) FROM recipes AS r
+             WHERE NOT {}
+                AND (r.id IN (SELECT recipe_id FROM recipe_ingredients
+                        WHERE ingredient_id IN ({from}))
+                    OR r.id IN (SELECT s.recipe_id FROM recipe_steps AS s
+                        JOIN recipe_step_ingredients AS si ON si.step_id = s.id
+                        WHERE si.ingredient_id IN ({from})))",
+            sharing::EDITABLE,
+            from = from
+        ),
+        named_params! { ":user_id": user.id },
+        |row| row.get(0),
+    )?;
+    if locked > 0 {
+        return Err(AppError::Forbidden(
+            "These ingredients are used by recipes you can't change".to_string(),
+        ));
+    }
+    let mut affected: Vec<usize> = vec![];
+    for &from in input.from.iter().filter(|&&id| id != target.id) {
+        affected.extend(merge_recipe_lines(&tx, from, target.id)?);
+        merge_pantry_item(&tx, from, target.id)?;
+        tx.execute(
+            "UPDATE shopping_list_items SET ingredient_id = :into WHERE ingredient_id = :from",
+            named_params! { ":into": target.id, ":from": from },
+        )?;
+        tx.execute(
+            "INSERT OR IGNORE INTO recipe_step_ingredients (step_id, ingredient_id)
+             SELECT step_id, :into FROM recipe_step_ingredients WHERE ingredient_id = :from",
+            named_params! { ":into": target.id, ":from": from },
+        )?;
+        tx.execute(
+            "DELETE FROM recipe_step_ingredients WHERE ingredient_id = :from",
+            named_params! { ":from": from },
+        )?;
+        tx.execute(
+            "DELETE FROM ingredients WHERE id = :from",
+            named_params! { ":from": from },
+        )?;
+    }
+    for recipe_id in affected {
+        fulltext::index_recipe(&tx, recipe_id)?;
+    }
+    tx.commit()?;
+    serve_json(200, &target)
+}
+
+// An amount of an ingredient, as a recipe line or pantry item has it.
+#[derive(Debug, PartialEq)]
+struct Amount {
+    quantity: Option<f64>,
+    unit: Option<String>,
+    note: Option<String>,
+}
+
+impl Amount {
+    // Adds `other` to this amount, for two lines that become the same
+    // ingredient. Amounts in the same unit, or both masses or volumes, are
+    // added up in this one's unit. Anything else is kept in the note, e.g.
+    // "and 2 cups", along with the other's note.
+    fn add(&mut self, other: Amount) {
+        let mut notes = vec![self.note.take(), other.note];
+        match (self.quantity, other.quantity) {
+            (None, Some(_)) => {
+                self.quantity = other.quantity;
+                self.unit = other.unit;
+            }
+            (Some(quantity), Some(more)) => {
+                match converted(more, other.unit.as_deref(), self.unit.as_deref()) {
+                    Some(more) => self.quantity = Some(quantity + more),
+                    None => {
+                        let mut parts = vec![units::format_quantity(more)];
+                        parts.extend(other.unit);
+                        notes.push(Some(format!("and {}", parts.join(" "))));
+                    }
+                }
+            }
+            _ => {}
+        }
+        let mut kept: Vec<String> = vec![];
+        for note in notes.into_iter().flatten() {
+            if !note.trim().is_empty() && !kept.contains(&note) {
+                kept.push(note);
+            }
+        }
+        self.note = (!kept.is_empty()).then(|| kept.join("; "));
+    }
+}
+
+// `quantity` of `unit` in `into`, if they're the same unit or both masses or
+// both volumes.
+fn converted(quantity: f64, unit: Option<&str>, into: Option<&str>) -> Option<f64> {
+    let lower = |unit: Option<&str>| unit.map(|u| u.trim().to_lowercase());
+    if lower(unit) == lower(into) {
+        return Some(quantity);
+    }
+    let (base, symbol) = units::to_base(quantity, unit?)?;
+    let (_, into_symbol) = units::to_base(1.0, into?)?;
+    match symbol == into_symbol {
+        true => units::from_base(base, into?),
+        false => None,
+    }
+}
+
+// Points every recipe line of `from` at `into`. A recipe that has both
+// keeps its `into` line, with the `from` line's amount added to it, and
+// returns the ids of the recipes it changed.
+fn merge_recipe_lines(tx: &Connection, from: usize, into: usize) -> rusqlite::Result<Vec<usize>> {
+    let mut stmt = tx.prepare(
+        "SELECT recipe_id, quantity, unit, note FROM recipe_ingredients
+         WHERE ingredient_id = :from",
+    )?;
+    let lines = stmt
+        .query_map(named_params! { ":from": from }, |row| {
+            Ok((
+                row.get::<_, usize>(0)?,
+                Amount {
+                    quantity: row.get(1)?,
+                    unit: row.get(2)?,
+                    note: row.get(3)?,
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<(usize, Amount)>>>()?;
+    let mut recipe_ids = vec![];
+    for (recipe_id, amount) in lines {
+        let existing = tx
+            .query_row(
+                "SELECT quantity, unit, note FROM recipe_ingredients
+                 WHERE recipe_id = :recipe_id AND ingredient_id = :into",
+                named_params! { ":recipe_id": recipe_id, ":into": into },
+                |row| {
+                    Ok(Amount {
+                        quantity: row.get(0)?,
+                        unit: row.get(1)?,
+                        note: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?;
+        match existing {
+            // Keeps the line where it was in the recipe.
+            None => {
+                tx.execute(
+                    "UPDATE recipe_ingredients SET ingredient_id = :into
+                     WHERE recipe_id = :recipe_id AND ingredient_id = :from",
+                    named_params! { ":recipe_id": recipe_id, ":from": from, ":into": into },
+                )?;
+            }
+            Some(mut total) => {
+                total.add(amount);
+                tx.execute(
+                    "UPDATE recipe_ingredients SET quantity = :quantity, unit = :unit, note = :note
+                     WHERE recipe_id = :recipe_id AND ingredient_id = :into",
+                    named_params! {
+                        ":recipe_id": recipe_id,
+                        ":into": into,
+                        ":quantity": total.quantity,
+                        ":unit": total.unit,
+                        ":note": total.note,
+                    },
+                )?;
+                tx.execute(
+                    "DELETE FROM recipe_ingredients
+                     WHERE recipe_id = :recipe_id AND ingredient_id = :from",
+                    named_params! { ":recipe_id": recipe_id, ":from": from },
+                )?;
+            }
+        }
+        recipe_ids.push(recipe_id);
+    }
+    Ok(recipe_ids)
+}
+
+// Moves the pantry's `from` to `into`. If the pantry has both, the amounts
+// are added up and the earlier expiry date is kept. The pantry has no notes,
+// so an amount that can't be added to the other one is left out.
+fn merge_pantry_item(tx: &Connection, from: usize, into: usize) -> rusqlite::Result<()> {
+    let item = |id: usize| {
+        tx.query_row(
+            "SELECT quantity, unit, expires_on FROM pantry WHERE ingredient_id = :id",
+            named_params! { ":id": id },
+            |row| {
+                Ok((
+                    Amount {
+                        quantity: row.get(0)?,
+                        unit: row.get(1)?,
+                        note: None,
+                    },
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            },
+        )
+        .optional()
+    };
+    let Some((amount, expires_on)) = item(from)? else {
+        return Ok(());
+    };
+    match item(into)? {
+        None => {
+            tx.execute(
+                "UPDATE pantry SET ingredient_id = :into WHERE ingredient_id = :from",
+                named_params! { ":into": into, ":from": from },
+            )?;
+        }
+        Some((mut total, kept_expires_on)) => {
+            total.add(amount);
+            let expires_on = match (kept_expires_on, expires_on) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            tx.execute(
+                "UPDATE pantry SET quantity = :quantity, unit = :unit, expires_on = :expires_on
+                 WHERE ingredient_id = :into",
+                named_params! {
+                    ":into": into,
+                    ":quantity": total.quantity,
+                    ":unit": total.unit,
+                    ":expires_on": expires_on,
+                },
+            )?;
+            tx.execute(
+                "DELETE FROM pantry WHERE ingredient_id = :from",
+                named_params! { ":from": from },
+            )?;
+        }
+    }
+    Ok(())
+}
+
+impl IngredientRef {
+    // The name, the way the forms send it. An id has to be one of `all`.
+    fn into_name(self, all: &[Ingredient]) -> Result<String> {
+        match self {
+            IngredientRef::Id(id) => all
+                .iter()
+                .find(|i| i.id == id)
+                .map(|i| i.name.clone())
+                .ok_or_else(|| AppError::Validation(format!("No ingredient has the id {}", id))),
+            IngredientRef::Name(name) => Ok(name.trim().to_string()),
+        }
+    }
+}
+
+fn step_lines(inputs: Vec<StepInput>, all: &[Ingredient]) -> Result<Vec<StepLine>> {
+    inputs
+        .into_iter()
+        .map(|input| match input {
+            StepInput::Step {
+                text,
+                timer_seconds,
+                ingredients,
+            } => Ok(StepLine {
+                text,
+                timer_seconds,
+                ingredients: ingredients
+                    .into_iter()
+                    .map(|i| i.into_name(all))
+                    .collect::<Result<Vec<String>>>()?,
+            }),
+            StepInput::Text(text) => Ok(StepLine {
+                text,
+                timer_seconds: None,
+                ingredients: vec![],
+            }),
+        })
+        .collect()
+}
+
+fn ingredient_lines(
+    inputs: Vec<IngredientInput>,
+    all: &[Ingredient],
+) -> Result<Vec<IngredientLine>> {
+    let lines = inputs
+        .into_iter()
+        .map(|input| match input {
+            IngredientInput::Line {
+                ingredient,
+                quantity,
+                unit,
+                note,
+            } => Ok(IngredientLine {
+                ingredient: ingredient.into_name(all)?,
+                quantity,
+                unit: unit.filter(|u| !u.trim().is_empty()),
+                note: note.filter(|n| !n.trim().is_empty()),
+            }),
+            IngredientInput::Plain(ingredient) => Ok(IngredientLine {
+                ingredient: ingredient.into_name(all)?,
+                quantity: None,
+                unit: None,
+                note: None,
+            }),
+        })
+        .collect::<Result<Vec<IngredientLine>>>()?;
+    Ok(lines
+        .into_iter()
+        .filter(|line| !line.ingredient.is_empty())
+        .collect())
+}
+
+// Reads a comma separated (or repeated) query parameter, e.g.
+// `ingredients=1,2&ingredients=3`.
+fn query_list(url: &str, key: &str) -> Vec<String> {
+    query_values(url, key)
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(|part| part.trim().to_string())
+        .filter(|part| !part.is_empty())
+        .collect()
+}
+
+// Only JSON bodies are read. A form on another site can't send those
+// without the browser asking us first, and we never agree, so it can't
+// change anything with the cookie or basic auth the browser has.
+fn read_json<T: for<'de> Deserialize<'de>>(request: &mut Request) -> Result<T> {
+    let json = find_header(request.headers(), "Content-Type".to_string())
+        .is_some_and(|h| h.value.as_str().starts_with("application/json"));
+    if !json {
+        return Err(AppError::Parse(
+            "Content-Type must be application/json".to_string(),
+        ));
+    }
+    let content = forms::read_body(request, forms::FORM_MAX)?;
+    serde_json::from_slice(&content).map_err(|e| AppError::Parse(format!("Invalid JSON: {}", e)))
+}
+
+fn serve_json<T: Serialize + ?Sized>(status: u16, value: &T) -> Result<ResponseBox> {
+    let body = serde_json::to_vec(value).expect("To serialize the response");
+    let content_type_header = Header::from_bytes("Content-Type", "application/json")
+        .expect("That we didn't put any garbage in the headers");
+    Ok(Response::from_data(body)
+        .with_header(content_type_header)
+        .with_status_code(status)
+        .boxed())
+}
+
+// For the statuses only the API answers with.
+fn serve_error(status: u16, message: &str) -> Result<ResponseBox> {
+    serve_json(status, &ApiError { error: message })
+}
+
+// A 405 that lists the methods the path takes.
+fn method_not_allowed(allow: &str) -> Result<ResponseBox> {
+    let allow_header =
+        Header::from_bytes("Allow", allow).expect("That we didn't put any garbage in the headers");
+    Ok(serve_error(405, "Method not allowed")?.with_header(allow_header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tiny_http::TestRequest;
+
+    // A user of their own, so tests don't see each other's recipes.
+    fn new_user(name: &str) -> User {
+        let con = get_con().unwrap();
+        con.execute(
+            "INSERT INTO users (name, password_hash) VALUES (:name, '')",
+            named_params! { ":name": format!("api-{}", name) },
+        )
+        .unwrap();
+        User {
+            id: con.last_insert_rowid() as usize,
+            name: name.to_string(),
+        }
+    }
+
+    fn call(
+        method: Method,
+        path: &str,
+        body: Option<String>,
+        user: &User,
+    ) -> (u16, serde_json::Value) {
+        let mut test = TestRequest::new()
+            .with_method(method)
+            .with_path(&format!("/api/v1/{}", path));
+        if let Some(body) = body {
+            test = test
+                .with_body(Box::leak(body.into_boxed_str()))
+                .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+        }
+        let mut request: Request = test.into();
+        match handle_api(&mut request, user) {
+            Ok(response) => {
+                let status = response.status_code().0;
+                let mut body = vec![];
+                response.into_reader().read_to_end(&mut body).unwrap();
+                (status, serde_json::from_slice(&body).unwrap_or_default())
+            }
+            Err(e) => (e.status(), serde_json::Value::Null),
+        }
+    }
+
+    fn create(name: &str, ingredients: &[&str], user: &User) -> usize {
+        let body = serde_json::json!({ "name": name, "ingredients": ingredients });
+        let (status, recipe) = call(Method::Post, "recipes", Some(body.to_string()), user);
+        assert_eq!(status, 201);
+        recipe["id"].as_u64().unwrap() as usize
+    }
+
+    fn ingredient_id(name: &str) -> usize {
+        get_all_ingredients()
+            .unwrap()
+            .into_iter()
+            .find(|i| i.name == name)
+            .unwrap()
+            .id
+    }
+
+    fn ingredient_ids(recipe_id: usize) -> Vec<usize> {
+        let con = get_con().unwrap();
+        let mut stmt = con
+            .prepare(
+                "SELECT ingredient_id FROM recipe_ingredients
+                 WHERE recipe_id = ? ORDER BY ingredient_id",
+            )
+            .unwrap();
+        let ids = stmt
+            .query_map([recipe_id], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<usize>>>()
+            .unwrap();
+        ids
+    }
+
+    #[test]
+    fn answers_with_the_right_status() {
+        let alice = new_user("alice");
+        let bob = new_user("bob");
+        let id = create("API status soup", &["api status water"], &alice);
+
+        let (status, recipe) = call(Method::Get, &format!("recipes/{}", id), None, &alice);
+        assert_eq!(status, 200);
+        assert_eq!(recipe["name"], "API status soup");
+        assert_eq!(call(Method::Get, "recipes/999999", None, &alice).0, 404);
+        assert_eq!(call(Method::Get, "recipes/soup", None, &alice).0, 404);
+        assert_eq!(call(Method::Get, "nothing", None, &alice).0, 404);
+        // Bob can't see Alice's private recipe at all.
+        assert_eq!(
+            call(Method::Get, &format!("recipes/{}", id), None, &bob).0,
+            404
+        );
+
+        assert_eq!(call(Method::Patch, "recipes", None, &alice).0, 405);
+        assert_eq!(
+            call(Method::Post, &format!("recipes/{}", id), None, &alice).0,
+            405
+        );
+        assert_eq!(call(Method::Get, "ingredients/merge", None, &alice).0, 405);
+        let allowed = |method: Method, path: &str| {
+            let mut request: Request = TestRequest::new()
+                .with_method(method)
+                .with_path(&format!("/api/v1/{}", path))
+                .into();
+            let response = handle_api(&mut request, &alice).unwrap();
+            response
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv("Allow"))
+                .map(|h| h.value.to_string())
+        };
+        assert_eq!(
+            allowed(Method::Patch, "recipes").as_deref(),
+            Some("GET, POST")
+        );
+        assert_eq!(
+            allowed(Method::Post, &format!("recipes/{}", id)).as_deref(),
+            Some("GET, PUT, DELETE")
+        );
+        assert_eq!(allowed(Method::Delete, "search").as_deref(), Some("GET"));
+        assert_eq!(
+            allowed(Method::Get, "ingredients/merge").as_deref(),
+            Some("POST")
+        );
+
+        let duplicate = serde_json::json!({ "name": "API status soup" }).to_string();
+        assert_eq!(call(Method::Post, "recipes", Some(duplicate), &bob).0, 409);
+        assert_eq!(
+            call(Method::Post, "recipes", Some("{".to_string()), &alice).0,
+            400
+        );
+    }
+
+    #[test]
+    fn only_lets_the_owner_change_a_recipe() {
+        let alice = new_user("carol");
+        let bob = new_user("dave");
+        let id = create("API owned stew", &[], &alice);
+        let con = get_con().unwrap();
+        con.execute(
+            "UPDATE recipes SET visibility = 'public' WHERE id = ?",
+            [id],
+        )
+        .unwrap();
+
+        let path = format!("recipes/{}", id);
+        let body = serde_json::json!({ "name": "API stolen stew" }).to_string();
+        assert_eq!(call(Method::Get, &path, None, &bob).0, 200);
+        assert_eq!(call(Method::Put, &path, Some(body.clone()), &bob).0, 403);
+        assert_eq!(call(Method::Delete, &path, None, &bob).0, 403);
+        assert_eq!(call(Method::Put, &path, Some(body), &alice).0, 200);
+        assert_eq!(call(Method::Delete, &path, None, &alice).0, 204);
+        assert_eq!(call(Method::Get, &path, None, &alice).0, 404);
+    }
+
+    #[test]
+    fn merges_ingredients_of_recipes_the_user_can_change() {
+        let alice = new_user("erin");
+        let bob = new_user("frank");
+        let salad = create(
+            "API merge salad",
+            &["api merge tomato", "api merge tomatoes"],
+            &alice,
+        );
+        let sauce = create("API merge sauce", &["api merge tomatos"], &alice);
+        let soup = create("API merge soup", &["api merge tomatos"], &bob);
+        let tomato = ingredient_id("api merge tomato");
+        let tomatoes = ingredient_id("api merge tomatoes");
+        let tomatos = ingredient_id("api merge tomatos");
+
+        let merge = |into: usize, from: &[usize], user: &User| {
+            let body = serde_json::json!({ "into": into, "from": from }).to_string();
+            call(Method::Post, "ingredients/merge", Some(body), user)
+        };
+        // Bob's private soup uses one of them, so Alice can't merge it.
+        assert_eq!(merge(tomato, &[tomatos], &alice).0, 403);
+        assert_eq!(ingredient_ids(soup), vec![tomatos]);
+        assert_eq!(merge(999999, &[tomatos], &alice).0, 404);
+
+        let (status, target) = merge(tomato, &[tomatoes, tomato], &alice);
+        assert_eq!(status, 200);
+        assert_eq!(target["name"], "api merge tomato");
+        assert_eq!(ingredient_ids(salad), vec![tomato]);
+        assert!(get_all_ingredients()
+            .unwrap()
+            .iter()
+            .all(|i| i.id != tomatoes));
+
+        let soup = format!("recipes/{}", soup);
+        assert_eq!(call(Method::Delete, &soup, None, &bob).0, 204);
+        assert_eq!(merge(tomato, &[tomatos], &alice).0, 200);
+        assert_eq!(ingredient_ids(sauce), vec![tomato]);
+    }
+
+    // (quantity, unit, note) of every line of a recipe.
+    fn amounts(recipe_id: usize) -> Vec<(Option<f64>, Option<String>, Option<String>)> {
+        let con = get_con().unwrap();
+        let mut stmt = con
+            .prepare(
+                "SELECT quantity, unit, note FROM recipe_ingredients
+                 WHERE recipe_id = ? ORDER BY rowid",
+            )
+            .unwrap();
+        let amounts = stmt
+            .query_map([recipe_id], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        amounts
+    }
+
+    #[test]
+    fn keeps_amounts_when_merging() {
+        let alice = new_user("grace");
+        let line = |name: &str, quantity: f64, unit: &str, note: &str| {
+            serde_json::json!({
+                "ingredient": name,
+                "quantity": quantity,
+                "unit": unit,
+                "note": note,
+            })
+        };
+        let create_with = |name: &str, lines: serde_json::Value| {
+            let body = serde_json::json!({ "name": name, "ingredients": lines }).to_string();
+            let (status, recipe) = call(Method::Post, "recipes", Some(body), &alice);
+            assert_eq!(status, 201);
+            recipe["id"].as_u64().unwrap() as usize
+        };
+        let both = create_with(
+            "API amount sauce",
+            serde_json::json!([
+                line("api amount flour", 200.0, "g", "sifted"),
+                line("api amount salt", 1.0, "tsp", ""),
+                line("api amount wheat flour", 0.5, "kg", "fine"),
+            ]),
+        );
+        let only_from = create_with(
+            "API amount bread",
+            serde_json::json!([line("api amount wheat flour", 3.0, "cups", "")]),
+        );
+        let mixed = create_with(
+            "API amount cake",
+            serde_json::json!([
+                line("api amount flour", 100.0, "g", ""),
+                line("api amount wheat flour", 2.0, "cups", ""),
+            ]),
+        );
+        let flour = ingredient_id("api amount flour");
+        let wheat = ingredient_id("api amount wheat flour");
+        let con = get_con().unwrap();
+        con.execute(
+            "INSERT INTO pantry (ingredient_id, quantity, unit, expires_on)
+             VALUES (?1, 1, 'kg', '2030-01-10'), (?2, 250, 'g', '2030-01-05')",
+            [flour, wheat],
+        )
+        .unwrap();
+        con.execute(
+            "INSERT INTO shopping_lists (name, created_on) VALUES ('API amounts', '2030-01-01')",
+            [],
+        )
+        .unwrap();
+        let list = con.last_insert_rowid();
+        con.execute(
+            "INSERT INTO shopping_list_items (list_id, ingredient_id, quantity, unit)
+             VALUES (?1, ?2, 2, 'kg')",
+            rusqlite::params![list, wheat],
+        )
+        .unwrap();
+
+        let body = serde_json::json!({ "into": flour, "from": [wheat] }).to_string();
+        assert_eq!(
+            call(Method::Post, "ingredients/merge", Some(body), &alice).0,
+            200
+        );
+
+        let some = |text: &str| Some(text.to_string());
+        assert_eq!(
+            amounts(both),
+            vec![
+                (Some(700.0), some("g"), some("sifted; fine")),
+                (Some(1.0), some("tsp"), None),
+            ]
+        );
+        assert_eq!(amounts(only_from), vec![(Some(3.0), some("cups"), None)]);
+        assert_eq!(ingredient_ids(only_from), vec![flour]);
+        // Cups of flour can't be added to grams.
+        assert_eq!(
+            amounts(mixed),
+            vec![(Some(100.0), some("g"), some("and 2 cups"))]
+        );
+
+        let pantry = con
+            .query_row(
+                "SELECT quantity, unit, expires_on FROM pantry WHERE ingredient_id = ?",
+                [flour],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(pantry, (1.25, "kg".to_string(), "2030-01-05".to_string()));
+        let leftover: usize = con
+            .query_row(
+                "SELECT count(*) FROM pantry WHERE ingredient_id = ?",
+                [wheat],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(leftover, 0);
+        let item: (usize, f64) = con
+            .query_row(
+                "SELECT ingredient_id, quantity FROM shopping_list_items WHERE list_id = ?",
+                [list],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(item, (flour, 2.0));
+    }
+
+    #[test]
+    fn takes_numbers_as_names() {
+        let alice = new_user("heidi");
+        create("API digits soup", &["api digits stock"], &alice);
+        let stock = ingredient_id("api digits stock");
+
+        // The name "12" is an ingredient called 12, an id is one that exists.
+        let body = serde_json::json!({
+            "name": "API digits stew",
+            "ingredients": [stock.to_string(), stock],
+            "steps": [{ "text": "Stir.", "ingredients": [stock.to_string()] }],
+        })
+        .to_string();
+        let (status, recipe) = call(Method::Post, "recipes", Some(body), &alice);
+        assert_eq!(status, 201);
+        let named = ingredient_id(&stock.to_string());
+        assert_ne!(named, stock);
+        let id = recipe["id"].as_u64().unwrap() as usize;
+        let mut expected = vec![stock, named];
+        expected.sort();
+        assert_eq!(ingredient_ids(id), expected);
+        assert_eq!(recipe["steps"][0]["ingredients"][0]["id"], named);
+
+        let body = serde_json::json!({ "name": "API digits pie", "ingredients": [999999] });
+        assert_eq!(
+            call(Method::Post, "recipes", Some(body.to_string()), &alice).0,
+            400
+        );
+    }
+}
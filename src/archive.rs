@@ -0,0 +1,569 @@
+// Backups of the whole recipe book as JSON or YAML, for moving it to
+// another machine or keeping it in version control.
+//
+//     recipe-helper export [--format json|yaml] [FILE]
+//     recipe-helper import [--replace] FILE.json|FILE.yaml...
+//
+// The format, version 1, refers to ingredients by name so archives can be
+// merged into any database. In YAML it looks like this, JSON has the same
+// fields:
+//
+//     format: recipe-helper
+//     version: 1
+//     exported_on: 2024-03-04
+//     ingredients:            # every ingredient, also unused ones
+//     - flour
+//     - milk
+//     recipes:
+//     - name: Pancakes
+//       description: Mix and fry.    # optional
+//       servings: 4                  # optional
+//       prep_minutes: 10             # optional, as are cook_ and total_
+//       ingredients:
+//       - ingredient: flour
+//         quantity: 200              # optional
+//         unit: g                    # optional
+//         note: sifted               # optional
+//       steps:                       # optional
+//       - text: Mix everything.
+//         timer_seconds: 600         # optional
+//         ingredients: [flour]       # optional, of the ones above
+//
+// Importing adds recipes whose name isn't taken yet. A recipe that exists
+// with different contents is a conflict: it is left alone and reported, or
+// replaced with `--replace`.
+
+use crate::dates::Date;
+use crate::db;
+use crate::errors::Result;
+use crate::users::User;
+use crate::{
+    get_all_ingredients, get_con, get_recipes, ingredients_by_name, load_recipe, recipe_id_by_name,
+    Ingredient, Recipe, RecipeIngredient, Step,
+};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs};
+use tiny_http::{Header, Response, ResponseBox};
+
+const FORMAT_NAME: &str = "recipe-helper";
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Json,
+    Yaml,
+}
+
+impl Format {
+    pub fn parse(text: &str) -> Option<Format> {
+        match text.to_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+
+    pub fn from_path(path: &str) -> Option<Format> {
+        Format::parse(path.rsplit_once('.')?.1)
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Archive {
+    format: String,
+    version: u32,
+    #[serde(default)]
+    exported_on: Option<String>,
+    #[serde(default)]
+    ingredients: Vec<String>,
+    #[serde(default)]
+    recipes: Vec<ArchivedRecipe>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct ArchivedRecipe {
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    servings: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prep_minutes: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cook_minutes: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_minutes: Option<u32>,
+    #[serde(default)]
+    ingredients: Vec<ArchivedIngredient>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    steps: Vec<ArchivedStep>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct ArchivedStep {
+    text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timer_seconds: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ingredients: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct ArchivedIngredient {
+    ingredient: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quantity: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+}
+
+impl From<&Recipe> for ArchivedRecipe {
+    fn from(recipe: &Recipe) -> Self {
+        ArchivedRecipe {
+            name: recipe.name.clone(),
+            // The forms save an empty description rather than none.
+            description: recipe.description.clone().filter(|d| !d.is_empty()),
+            servings: recipe.servings,
+            prep_minutes: recipe.prep_minutes,
+            cook_minutes: recipe.cook_minutes,
+            total_minutes: recipe.total_minutes,
+            ingredients: recipe
+                .ingredients
+                .iter()
+                .map(|i| ArchivedIngredient {
+                    ingredient: i.ingredient.name.clone(),
+                    quantity: i.quantity,
+                    unit: i.unit.clone(),
+                    note: i.note.clone(),
+                })
+                .collect(),
+            steps: recipe
+                .steps
+                .iter()
+                .map(|s| ArchivedStep {
+                    text: s.text.clone(),
+                    timer_seconds: s.timer_seconds,
+                    ingredients: s.ingredients.iter().map(|i| i.name.clone()).collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+// The recipe's ingredients a step names, in the step's order.
+fn step_ingredients(names: &[String], ingredients: &[RecipeIngredient]) -> Vec<Ingredient> {
+    let mut linked: Vec<Ingredient> = vec![];
+    for name in names {
+        let found = ingredients
+            .iter()
+            .find(|i| i.ingredient.name.eq_ignore_ascii_case(name.trim()));
+        if let Some(i) = found {
+            if !linked.iter().any(|l| l.id == i.ingredient.id) {
+                linked.push(i.ingredient.clone());
+            }
+        }
+    }
+    linked
+}
+
+impl ArchivedRecipe {
+    // Copies everything but the id into `recipe`.
+    fn apply_to(self, con: &Connection, recipe: &mut Recipe) -> rusqlite::Result<()> {
+        let names = self
+            .ingredients
+            .iter()
+            .map(|i| i.ingredient.clone())
+            .collect::<Vec<String>>();
+        let found = ingredients_by_name(con, &names)?;
+        recipe.name = self.name;
+        recipe.ingredients = vec![];
+        for line in self.ingredients {
+            let Some(ingredient) = found.iter().find(|i| i.name == line.ingredient) else {
+                continue;
+            };
+            if recipe
+                .ingredients
+                .iter()
+                .any(|i| i.ingredient.id == ingredient.id)
+            {
+                continue;
+            }
+            recipe.ingredients.push(RecipeIngredient {
+                ingredient: ingredient.clone(),
+                quantity: line.quantity,
+                unit: line.unit,
+                note: line.note,
+            });
+        }
+        recipe.steps = self
+            .steps
+            .into_iter()
+            .filter(|s| !s.text.trim().is_empty())
+            .map(|s| Step {
+                text: s.text.trim().to_string(),
+                timer_seconds: s.timer_seconds.filter(|t| *t > 0),
+                ingredients: step_ingredients(&s.ingredients, &recipe.ingredients),
+            })
+            .collect();
+        recipe.description = self.description;
+        recipe.servings = self.servings;
+        recipe.prep_minutes = self.prep_minutes;
+        recipe.cook_minutes = self.cook_minutes;
+        recipe.total_minutes = self.total_minutes;
+        Ok(())
+    }
+}
+
+#[derive(Default, Debug)]
+struct ImportReport {
+    added: Vec<String>,
+    unchanged: Vec<String>,
+    replaced: Vec<String>,
+    // Recipes that exist with different contents and were left alone.
+    conflicts: Vec<String>,
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} unchanged, {} replaced, {} conflicting",
+            self.added.len(),
+            self.unchanged.len(),
+            self.replaced.len(),
+            self.conflicts.len()
+        )?;
+        for name in self.conflicts.iter() {
+            write!(
+                f,
+                "\n  conflict: \"{}\" exists with different contents, kept the existing one (--replace overwrites it)",
+                name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Everything the user can see, or every recipe without a user.
+fn build_archive(user: Option<&User>) -> rusqlite::Result<Archive> {
+    let mut ingredients = get_all_ingredients()?
+        .into_iter()
+        .map(|i| i.name)
+        .collect::<Vec<String>>();
+    ingredients.sort();
+    let ids = match user {
+        Some(user) => get_recipes(user)?.into_iter().map(|r| r.id).collect(),
+        None => all_recipe_ids()?,
+    };
+    let mut recipes = vec![];
+    for id in ids {
+        if let Some(recipe) = load_recipe(&*get_con()?, id)? {
+            recipes.push(ArchivedRecipe::from(&recipe));
+        }
+    }
+    recipes.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Archive {
+        format: FORMAT_NAME.to_string(),
+        version: FORMAT_VERSION,
+        exported_on: Some(Date::today().to_string()),
+        ingredients,
+        recipes,
+    })
+}
+
+fn serialize(archive: &Archive, format: Format) -> String {
+    match format {
+        Format::Json => serde_json::to_string_pretty(archive).expect("To serialize the archive"),
+        Format::Yaml => serde_yaml::to_string(archive).expect("To serialize the archive"),
+    }
+}
+
+fn deserialize(text: &str, format: Format) -> std::result::Result<Archive, String> {
+    let archive: Archive = match format {
+        Format::Json => serde_json::from_str(text).map_err(|e| e.to_string())?,
+        Format::Yaml => serde_yaml::from_str(text).map_err(|e| e.to_string())?,
+    };
+    if archive.format != FORMAT_NAME {
+        return Err(format!("not a {} archive", FORMAT_NAME));
+    }
+    if archive.version > FORMAT_VERSION {
+        return Err(format!(
+            "archive version {} is newer than the latest known version {}",
+            archive.version, FORMAT_VERSION
+        ));
+    }
+    Ok(archive)
+}
+
+fn all_recipe_ids() -> rusqlite::Result<Vec<usize>> {
+    let con = get_con()?;
+    let mut stmt = con.prepare("SELECT id FROM recipes")?;
+    let ids = stmt.query_map((), |row| row.get(0))?.collect();
+    ids
+}
+
+pub fn export(format: Format, user: Option<&User>) -> rusqlite::Result<String> {
+    Ok(serialize(&build_archive(user)?, format))
+}
+
+// Merges an archive into the database by recipe and ingredient name and
+// returns what happened as text.
+pub fn import(text: &str, format: Format, replace: bool) -> std::result::Result<String, String> {
+    let archive = deserialize(text, format)?;
+    import_archive(archive, replace).map_err(|e| e.to_string())
+}
+
+// All or nothing, a failure halfway leaves the database as it was.
+fn import_archive(archive: Archive, replace: bool) -> Result<String> {
+    let mut con = get_con()?;
+    let tx = db::write_transaction(&mut con)?;
+    ingredients_by_name(&tx, &archive.ingredients)?;
+    let mut report = ImportReport::default();
+    for mut archived in archive.recipes {
+        let name = archived.name.trim().to_string();
+        if name.is_empty() {
+            continue;
+        }
+        archived.name = name.clone();
+        let existing = match recipe_id_by_name(&tx, &name)? {
+            Some(id) => load_recipe(&tx, id)?,
+            None => None,
+        };
+        match existing {
+            None => {
+                let mut recipe = Recipe::default();
+                archived.apply_to(&tx, &mut recipe)?;
+                recipe.insert(&tx)?;
+                report.added.push(name);
+            }
+            Some(recipe) if ArchivedRecipe::from(&recipe) == archived => {
+                report.unchanged.push(name);
+            }
+            Some(mut recipe) if replace => {
+                archived.apply_to(&tx, &mut recipe)?;
+                recipe.update(&tx)?;
+                report.replaced.push(name);
+            }
+            Some(_) => report.conflicts.push(name),
+        }
+    }
+    tx.commit()?;
+    Ok(report.to_string())
+}
+
+// Downloads at /export.json and /export.yaml.
+pub fn export_download(format: Format, user: &User) -> Result<ResponseBox> {
+    let (content_type, extension) = match format {
+        Format::Json => ("application/json; charset=utf-8", "json"),
+        Format::Yaml => ("application/yaml; charset=utf-8", "yaml"),
+    };
+    let disposition = format!(
+        "attachment; filename=\"recipes-{}.{}\"",
+        Date::today(),
+        extension
+    );
+    let response = Response::from_data(export(format, Some(user))?)
+        .with_header(
+            Header::from_bytes("Content-Type", content_type)
+                .expect("That we didn't put any garbage in the headers"),
+        )
+        .with_header(
+            Header::from_bytes("Content-Disposition", disposition)
+                .expect("That we didn't put any garbage in the headers"),
+        );
+    Ok(response.boxed())
+}
+
+// `recipe-helper export [--format json|yaml] [FILE]`, to standard output
+// without a file.
+pub fn export_command(args: &[String]) {
+    let mut format = None;
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = args.next().and_then(|f| Format::parse(f)),
+            _ => path = Some(arg.clone()),
+        }
+    }
+    let Some(format) = format
+        .or_else(|| path.as_deref().and_then(Format::from_path))
+        .or(path.is_none().then_some(Format::Json))
+    else {
+        eprintln!("usage: recipe-helper export [--format json|yaml] [FILE.json|FILE.yaml]");
+        std::process::exit(2);
+    };
+    let text = match export(format, None) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    match path {
+        Some(path) => {
+            if let Err(e) = fs::write(&path, text) {
+                eprintln!("{}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+        None => print!("{}", text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Archive {
+        Archive {
+            format: FORMAT_NAME.to_string(),
+            version: FORMAT_VERSION,
+            exported_on: Some("2024-03-04".to_string()),
+            ingredients: vec!["flour".to_string(), "milk".to_string()],
+            recipes: vec![ArchivedRecipe {
+                name: "Pancakes".to_string(),
+                description: Some("Mix and fry.".to_string()),
+                servings: Some(4),
+                prep_minutes: Some(10),
+                cook_minutes: None,
+                total_minutes: None,
+                ingredients: vec![
+                    ArchivedIngredient {
+                        ingredient: "flour".to_string(),
+                        quantity: Some(200.0),
+                        unit: Some("g".to_string()),
+                        note: Some("sifted".to_string()),
+                    },
+                    ArchivedIngredient {
+                        ingredient: "milk".to_string(),
+                        quantity: None,
+                        unit: None,
+                        note: None,
+                    },
+                ],
+                steps: vec![ArchivedStep {
+                    text: "Mix everything.".to_string(),
+                    timer_seconds: Some(600),
+                    ingredients: vec!["flour".to_string()],
+                }],
+            }],
+        }
+    }
+
+    #[test]
+    fn round_trips_both_formats() {
+        for format in [Format::Json, Format::Yaml] {
+            let text = serialize(&sample(), format);
+            assert_eq!(deserialize(&text, format).unwrap(), sample());
+        }
+    }
+
+    #[test]
+    fn matches_the_documented_yaml() {
+        let text = "format: recipe-helper
+version: 1
+recipes:
+- name: Pancakes
+  ingredients:
+  - ingredient: flour
+    quantity: 200
+    unit: g
+  - ingredient: milk
+";
+        let archive = deserialize(text, Format::Yaml).unwrap();
+        assert_eq!(archive.recipes[0].ingredients[0].quantity, Some(200.0));
+        assert_eq!(archive.recipes[0].ingredients[1].unit, None);
+        let yaml = serialize(&sample(), Format::Yaml);
+        assert!(yaml.contains("  servings: 4\n"));
+        assert!(!yaml.contains("cook_minutes"));
+    }
+
+    #[test]
+    fn rejects_other_and_newer_archives() {
+        assert!(deserialize("{\"format\": \"other\", \"version\": 1}", Format::Json).is_err());
+        let newer = format!("{{\"format\": \"{}\", \"version\": 2}}", FORMAT_NAME);
+        assert!(deserialize(&newer, Format::Json)
+            .unwrap_err()
+            .contains("newer"));
+        assert_eq!(Format::from_path("backup.YML"), Some(Format::Yaml));
+        assert_eq!(Format::from_path("backup"), None);
+    }
+
+    fn recipe(name: &str, ingredients: &[&str]) -> ArchivedRecipe {
+        ArchivedRecipe {
+            name: name.to_string(),
+            description: None,
+            servings: None,
+            prep_minutes: None,
+            cook_minutes: None,
+            total_minutes: None,
+            ingredients: ingredients
+                .iter()
+                .map(|name| ArchivedIngredient {
+                    ingredient: name.to_string(),
+                    quantity: None,
+                    unit: None,
+                    note: None,
+                })
+                .collect(),
+            steps: vec![ArchivedStep {
+                text: "Stir.".to_string(),
+                timer_seconds: None,
+                ingredients: ingredients.iter().map(|name| name.to_string()).collect(),
+            }],
+        }
+    }
+
+    #[test]
+    fn imports_ingredients_by_name_only() {
+        let con = get_con().unwrap();
+        con.execute("INSERT INTO ingredients (name) VALUES ('archive salt')", [])
+            .unwrap();
+        let salt = con.last_insert_rowid();
+        let mut archive = sample();
+        archive.ingredients = vec![salt.to_string()];
+        archive.recipes = vec![recipe("Archive numbers", &[&salt.to_string()])];
+        assert_eq!(
+            import_archive(archive, false).unwrap(),
+            "1 added, 0 unchanged, 0 replaced, 0 conflicting"
+        );
+
+        let id = recipe_id_by_name(&con, "Archive numbers").unwrap().unwrap();
+        let imported = load_recipe(&con, id).unwrap().unwrap();
+        let ingredient = &imported.ingredients[0].ingredient;
+        assert_eq!(ingredient.name, salt.to_string());
+        assert_ne!(ingredient.id as i64, salt);
+        assert_eq!(imported.steps[0].ingredients[0].id, ingredient.id);
+    }
+
+    #[test]
+    fn imports_all_or_nothing() {
+        let con = get_con().unwrap();
+        con.execute_batch(
+            "CREATE TRIGGER archive_refuse AFTER INSERT ON recipes
+             WHEN new.name = 'Archive broken'
+             BEGIN SELECT RAISE(ABORT, 'refused'); END;",
+        )
+        .unwrap();
+        let mut archive = sample();
+        archive.ingredients = vec!["archive saffron".to_string()];
+        archive.recipes = vec![
+            recipe("Archive paella", &["archive saffron", "archive rice"]),
+            recipe("Archive broken", &[]),
+        ];
+        assert!(import_archive(archive, false).is_err());
+
+        assert_eq!(recipe_id_by_name(&con, "Archive paella").unwrap(), None);
+        let names = get_all_ingredients()
+            .unwrap()
+            .into_iter()
+            .map(|i| i.name)
+            .collect::<Vec<String>>();
+        assert!(!names.contains(&"archive saffron".to_string()));
+        assert!(!names.contains(&"archive rice".to_string()));
+    }
+}
@@ -0,0 +1,83 @@
+// Templates and static files. They are built into the binary, so it runs
+// from any directory. With `--assets-dir DIR` they are read from DIR on
+// every request instead, e.g. `--assets-dir src` to see changes without
+// rebuilding. Only the files listed here are ever served.
+
+use std::borrow::Cow;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+use std::{env, fs};
+
+static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+const FILES: &[(&str, &[u8])] = &[
+    ("add.html", include_bytes!("add.html")),
+    ("cook.html", include_bytes!("cook.html")),
+    ("delete.html", include_bytes!("delete.html")),
+    ("error.html", include_bytes!("error.html")),
+    ("import.html", include_bytes!("import.html")),
+    ("login.html", include_bytes!("login.html")),
+    ("page.html", include_bytes!("page.html")),
+    ("pantry.html", include_bytes!("pantry.html")),
+    ("plan.html", include_bytes!("plan.html")),
+    ("recipe-body.html", include_bytes!("recipe-body.html")),
+    ("recipe.html", include_bytes!("recipe.html")),
+    ("search.html", include_bytes!("search.html")),
+    ("shared.html", include_bytes!("shared.html")),
+    ("shopping-list.html", include_bytes!("shopping-list.html")),
+    ("shopping.html", include_bytes!("shopping.html")),
+    ("jquery-3.7.0.min.js", include_bytes!("jquery-3.7.0.min.js")),
+    ("select2.min.css", include_bytes!("select2.min.css")),
+    ("select2.min.js", include_bytes!("select2.min.js")),
+];
+
+pub fn set_dir(dir: PathBuf) {
+    DIR.set(dir).expect("To set the assets directory only once");
+}
+
+// The asset called `name`. A file missing from the assets directory falls
+// back to the built in one.
+pub fn get(name: &str) -> Option<Cow<'static, [u8]>> {
+    let (_, embedded) = FILES.iter().find(|(file, _)| *file == name)?;
+    if let Some(dir) = DIR.get() {
+        if let Ok(bytes) = fs::read(dir.join(name)) {
+            return Some(Cow::Owned(bytes));
+        }
+    }
+    Some(Cow::Borrowed(embedded))
+}
+
+// When the asset last changed: the file's time in the assets directory, or
+// when the binary was built, which is when the built in ones changed.
+pub fn modified(name: &str) -> Option<SystemTime> {
+    let from_dir = DIR
+        .get()
+        .and_then(|dir| fs::metadata(dir.join(name)).ok())
+        .and_then(|metadata| metadata.modified().ok());
+    from_dir.or_else(|| {
+        env::current_exe()
+            .and_then(fs::metadata)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    })
+}
+
+// A template, which has to exist.
+pub fn text(name: &str) -> String {
+    let bytes = get(name).expect("To have the template");
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serves_only_listed_files() {
+        assert!(text("page.html").contains("{body}"));
+        assert!(get("select2.min.js").is_some());
+        assert!(get("main.rs").is_none());
+        assert!(get("../Cargo.toml").is_none());
+    }
+}
@@ -0,0 +1,556 @@
+// Cook mode: one step at a time in large type, for a tablet in the
+// kitchen. Timers are kept in the database and counted from their start
+// time, so reloading the page or opening it on another device shows the
+// same running timers. Each user only sees, and can only stop, the timers
+// they started. A paused timer stands still until it's resumed.
+
+use crate::dates::now;
+use crate::errors::Result;
+use crate::users::{self, User};
+use crate::{
+    escape_html, format_duration, get_con, get_recipe_by_id, get_usize, markdown, not_found,
+    query_value, return_redirect, serve_bytes, serve_html, template::Template, Recipe,
+};
+use rusqlite::{named_params, Connection};
+use serde::Serialize;
+use tiny_http::{Method, Request, ResponseBox};
+
+// Finished timers are cleared this long after they ran out.
+const KEEP_FINISHED_SECONDS: i64 = 3600;
+
+#[derive(Serialize, Debug, PartialEq)]
+struct Timer {
+    id: usize,
+    // Zero-based position of the step that started it.
+    step: usize,
+    label: String,
+    duration_seconds: u32,
+    remaining_seconds: u32,
+    paused: bool,
+    finished: bool,
+}
+
+fn remaining(started_at: i64, duration_seconds: u32, now: i64) -> u32 {
+    (started_at + duration_seconds as i64 - now).max(0) as u32
+}
+
+// The user's timers for the recipe as they are at `now`, leaving out the
+// ones that ran out a while ago. Polled often, so it only reads; the
+// starting and cancelling requests clear old timers away.
+fn get_timers(
+    con: &Connection,
+    recipe_id: usize,
+    user_id: usize,
+    now: i64,
+) -> rusqlite::Result<Vec<Timer>> {
+    let mut stmt = con.prepare(
+        "SELECT id, step, label, duration_seconds, started_at, paused_at FROM cook_timers
+         WHERE recipe_id = :recipe_id AND user_id = :user_id
+            AND (paused_at IS NOT NULL OR started_at + duration_seconds >= :limit)
+         ORDER BY started_at + duration_seconds",
+    )?;
+    let params = named_params! {
+        ":recipe_id": recipe_id,
+        ":user_id": user_id,
+        ":limit": now - KEEP_FINISHED_SECONDS,
+    };
+    let timers = stmt
+        .query_map(params, |row| {
+            let duration_seconds = row.get(3)?;
+            let paused_at: Option<i64> = row.get(5)?;
+            let remaining_seconds =
+                remaining(row.get(4)?, duration_seconds, paused_at.unwrap_or(now));
+            Ok(Timer {
+                id: row.get(0)?,
+                step: row.get(1)?,
+                label: row.get(2)?,
+                duration_seconds,
+                remaining_seconds,
+                paused: paused_at.is_some(),
+                finished: remaining_seconds == 0,
+            })
+        })?
+        .collect();
+    timers
+}
+
+// Deletes everyone's timers that ran out a while ago.
+fn clear_finished_timers(con: &Connection, now: i64) -> rusqlite::Result<()> {
+    con.execute(
+        "DELETE FROM cook_timers
+         WHERE paused_at IS NULL AND started_at + duration_seconds < :limit",
+        named_params! { ":limit": now - KEEP_FINISHED_SECONDS },
+    )?;
+    Ok(())
+}
+
+// Starts the timer of a step. A step's timer that is still running, or
+// paused, isn't started twice.
+fn start_timer(
+    con: &Connection,
+    recipe: &Recipe,
+    user_id: usize,
+    position: usize,
+    now: i64,
+) -> rusqlite::Result<()> {
+    let Some(duration) = recipe
+        .steps
+        .get(position)
+        .and_then(|step| step.timer_seconds)
+    else {
+        return Ok(());
+    };
+    clear_finished_timers(con, now)?;
+    let running = get_timers(con, recipe.id, user_id, now)?
+        .iter()
+        .any(|t| t.step == position && !t.finished);
+    if !running {
+        con.execute(
+            "INSERT INTO cook_timers
+                (recipe_id, user_id, step, label, duration_seconds, started_at)
+             VALUES (:recipe_id, :user_id, :step, :label, :duration_seconds, :started_at)",
+            named_params! {
+                ":recipe_id": recipe.id,
+                ":user_id": user_id,
+                ":step": position,
+                ":label": format!("Step {}", position + 1),
+                ":duration_seconds": duration,
+                ":started_at": now,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+// A timer as named in a request. Changes to timers of another recipe or
+// another user are ignored.
+struct TimerKey {
+    id: usize,
+    recipe_id: usize,
+    user_id: usize,
+}
+
+// Only a timer that is still counting down can be paused.
+fn pause_timer(con: &Connection, timer: &TimerKey, now: i64) -> rusqlite::Result<()> {
+    con.execute(
+        "UPDATE cook_timers SET paused_at = :now
+         WHERE id = :id AND recipe_id = :recipe_id AND user_id = :user_id
+            AND paused_at IS NULL AND started_at + duration_seconds > :now",
+        named_params! {
+            ":id": timer.id,
+            ":recipe_id": timer.recipe_id,
+            ":user_id": timer.user_id,
+            ":now": now,
+        },
+    )?;
+    Ok(())
+}
+
+fn resume_timer(con: &Connection, timer: &TimerKey, now: i64) -> rusqlite::Result<()> {
+    con.execute(
+        "UPDATE cook_timers SET started_at = started_at + :now - paused_at, paused_at = NULL
+         WHERE id = :id AND recipe_id = :recipe_id AND user_id = :user_id
+            AND paused_at IS NOT NULL",
+        named_params! {
+            ":id": timer.id,
+            ":recipe_id": timer.recipe_id,
+            ":user_id": timer.user_id,
+            ":now": now,
+        },
+    )?;
+    Ok(())
+}
+
+fn cancel_timer(con: &Connection, timer: &TimerKey, now: i64) -> rusqlite::Result<()> {
+    clear_finished_timers(con, now)?;
+    con.execute(
+        "DELETE FROM cook_timers
+         WHERE id = :id AND recipe_id = :recipe_id AND user_id = :user_id",
+        named_params! {
+            ":id": timer.id,
+            ":recipe_id": timer.recipe_id,
+            ":user_id": timer.user_id,
+        },
+    )?;
+    Ok(())
+}
+
+// Entry point for everything under /recipe/{id}/cook.
+pub fn handle_cook(request: &mut Request, user: &User) -> Result<ResponseBox> {
+    let url = request.url().to_string();
+    let path = url.split('?').next().unwrap_or_default();
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<&str>>();
+    let recipe = match segments.get(1).and_then(|id| get_usize(id)) {
+        Some(id) => get_recipe_by_id(id, user)?,
+        None => None,
+    };
+    let Some(recipe) = recipe else {
+        return Err(not_found("Recipe not found."));
+    };
+    let method = request.method().clone();
+    match (method, &segments[2..]) {
+        (Method::Get, ["cook"]) => cook_page(request, recipe, user),
+        (Method::Get, ["cook", "timers"]) => {
+            let timers = get_timers(&*get_con()?, recipe.id, user.id, now())?;
+            let body = serde_json::to_string(&timers).expect("To serialize the timers");
+            serve_bytes(body.as_bytes(), "application/json")
+        }
+        (Method::Post, ["cook", "timers"]) => start_timer_post(request, recipe, user),
+        (Method::Post, ["cook", "timers", timer, action]) => {
+            users::read_checked_form(request)?;
+            if let Some(id) = get_usize(timer) {
+                let con = get_con()?;
+                let timer = TimerKey {
+                    id,
+                    recipe_id: recipe.id,
+                    user_id: user.id,
+                };
+                match *action {
+                    "pause" => pause_timer(&con, &timer, now())?,
+                    "resume" => resume_timer(&con, &timer, now())?,
+                    "cancel" => cancel_timer(&con, &timer, now())?,
+                    _ => {}
+                }
+            }
+            let step = query_value(&url, "step").unwrap_or_default();
+            return_redirect(format!("/recipe/{}/cook?step={}", recipe.id, step))
+        }
+        _ => return_redirect(format!("/recipe/{}/cook", recipe.id)),
+    }
+}
+
+fn start_timer_post(request: &mut Request, recipe: Recipe, user: &User) -> Result<ResponseBox> {
+    let param_map = users::read_checked_form(request)?;
+    let position = param_map.get("step").and_then(|s| get_usize(s));
+    if let Some(position) = position {
+        start_timer(&*get_con()?, &recipe, user.id, position, now())?;
+    }
+    return_redirect(format!(
+        "/recipe/{}/cook?step={}",
+        recipe.id,
+        position.unwrap_or_default()
+    ))
+}
+
+fn timers_html(recipe_id: usize, step: usize, timers: &[Timer], csrf_field: &str) -> String {
+    timers
+        .iter()
+        .map(|timer| {
+            let class = if timer.finished {
+                "timer finished"
+            } else if timer.paused {
+                "timer paused"
+            } else {
+                "timer"
+            };
+            let url = format!("/recipe/{}/cook/timers/{}", recipe_id, timer.id);
+            let pause = match (timer.finished, timer.paused) {
+                (true, _) => String::new(),
+                (false, true) => format!(
+                    "<button type=\"submit\" formaction=\"{}/resume?step={}\">Resume</button>",
+                    url, step
+                ),
+                (false, false) => format!(
+                    "<button type=\"submit\" formaction=\"{}/pause?step={}\">Pause</button>",
+                    url, step
+                ),
+            };
+            format!(
+                "<form class=\"{}\" data-id=\"{}\" action=\"{}/cancel?step={}\" method=\"POST\">
+                    {}
+                    <span class=\"timer-label\">{}</span>
+                    <span class=\"timer-remaining\">{}</span>
+                    {}
+                    <button type=\"submit\">{}</button>
+                </form>",
+                class,
+                timer.id,
+                url,
+                step,
+                csrf_field,
+                escape_html(&timer.label),
+                format_clock(timer.remaining_seconds),
+                pause,
+                if timer.finished { "Done" } else { "Cancel" }
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("")
+}
+
+// "1:05:00", "4:30", "0:09".
+fn format_clock(seconds: u32) -> String {
+    match seconds / 3600 {
+        0 => format!("{}:{:02}", seconds / 60, seconds % 60),
+        hours => format!("{}:{:02}:{:02}", hours, seconds / 60 % 60, seconds % 60),
+    }
+}
+
+fn cook_page(request: &Request, recipe: Recipe, user: &User) -> Result<ResponseBox> {
+    let count = recipe.steps.len();
+    let step = query_value(request.url(), "step")
+        .and_then(|s| get_usize(&s))
+        .unwrap_or_default()
+        .min(count.saturating_sub(1));
+    let mut page = Template::page("cook.html");
+    let csrf_field = users::csrf_field(request)?;
+    let (text, details, timer_button) = match recipe.steps.get(step) {
+        Some(current) => {
+            let details = current
+                .ingredients
+                .iter()
+                .map(|i| {
+                    recipe
+                        .ingredients
+                        .iter()
+                        .find(|ri| ri.ingredient.id == i.id)
+                        .map(|ri| ri.render())
+                        .unwrap_or_else(|| i.name.clone())
+                })
+                .map(|line| format!("<li>{}</li>", escape_html(&line)))
+                .collect::<Vec<String>>()
+                .join("");
+            let timer_button = current
+                .timer_seconds
+                .map(|seconds| {
+                    format!(
+                        "<form action=\"/recipe/{}/cook/timers\" method=\"POST\">
+                            {}
+                            <input type=\"hidden\" name=\"step\" value=\"{}\" />
+                            <button type=\"submit\" class=\"start-timer\">⏱ Start {} timer</button>
+                        </form>",
+                        recipe.id,
+                        csrf_field,
+                        step,
+                        format_duration(seconds)
+                    )
+                })
+                .unwrap_or_default();
+            (markdown::render(&current.text), details, timer_button)
+        }
+        None => (
+            "This recipe has no steps yet.".to_string(),
+            String::new(),
+            String::new(),
+        ),
+    };
+    let link = |target: Option<usize>, label: &str| match target {
+        Some(target) => format!(
+            "<a class=\"button\" href=\"/recipe/{}/cook?step={}\">{}</a>",
+            recipe.id, target, label
+        ),
+        None => String::new(),
+    };
+    let previous = link(step.checked_sub(1), "← Previous");
+    let next = link(Some(step + 1).filter(|s| *s < count), "Next →");
+
+    page.set("id", recipe.id);
+    page.set("name", &recipe.name);
+    page.set(
+        "position",
+        format!("Step {} of {}", (step + 1).min(count), count),
+    );
+    page.set_html("previous", previous);
+    page.set_html("next", next);
+    page.set("step", step);
+    page.set_html("timer_button", timer_button);
+    page.set_html(
+        "timers",
+        timers_html(
+            recipe.id,
+            step,
+            &get_timers(&*get_con()?, recipe.id, user.id, now())?,
+            &csrf_field,
+        ),
+    );
+    page.set_html("ingredients", details);
+    page.set_html("text", text);
+    serve_html(request, page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{migrations, Step};
+
+    // Recipe 1, with a 10 minute timer on its first step only.
+    fn setup() -> (Connection, Recipe) {
+        let mut con = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut con).unwrap();
+        let step = |text: &str, timer_seconds| Step {
+            text: text.to_string(),
+            timer_seconds,
+            ingredients: vec![],
+        };
+        let recipe = Recipe {
+            id: 1,
+            steps: vec![step("Simmer.", Some(600)), step("Serve.", None)],
+            ..Recipe::default()
+        };
+        (con, recipe)
+    }
+
+    // Everything below is done by user 1 unless it says otherwise.
+    fn key(id: usize) -> TimerKey {
+        TimerKey {
+            id,
+            recipe_id: 1,
+            user_id: 1,
+        }
+    }
+
+    // (remaining seconds, paused, finished) of every timer.
+    fn states(con: &Connection, now: i64) -> Vec<(u32, bool, bool)> {
+        get_timers(con, 1, 1, now)
+            .unwrap()
+            .iter()
+            .map(|t| (t.remaining_seconds, t.paused, t.finished))
+            .collect()
+    }
+
+    #[test]
+    fn counts_down_from_the_start_time() {
+        assert_eq!(remaining(1000, 600, 1000), 600);
+        assert_eq!(remaining(1000, 600, 1590), 10);
+        assert_eq!(remaining(1000, 600, 2000), 0);
+    }
+
+    #[test]
+    fn formats_remaining_time() {
+        assert_eq!(format_clock(9), "0:09");
+        assert_eq!(format_clock(270), "4:30");
+        assert_eq!(format_clock(3900), "1:05:00");
+    }
+
+    #[test]
+    fn starts_a_steps_timer_once() {
+        let (con, recipe) = setup();
+        start_timer(&con, &recipe, 1, 1, 1000).unwrap();
+        start_timer(&con, &recipe, 1, 5, 1000).unwrap();
+        assert!(states(&con, 1000).is_empty());
+
+        start_timer(&con, &recipe, 1, 0, 1000).unwrap();
+        start_timer(&con, &recipe, 1, 0, 1100).unwrap();
+        assert_eq!(states(&con, 1100), vec![(500, false, false)]);
+        // Another one once it ran out.
+        start_timer(&con, &recipe, 1, 0, 1600).unwrap();
+        assert_eq!(
+            states(&con, 1700),
+            vec![(0, false, true), (500, false, false)]
+        );
+    }
+
+    #[test]
+    fn pauses_and_resumes() {
+        let (con, recipe) = setup();
+        start_timer(&con, &recipe, 1, 0, 1000).unwrap();
+        let id = get_timers(&con, 1, 1, 1000).unwrap()[0].id;
+
+        pause_timer(&con, &key(id), 1100).unwrap();
+        assert_eq!(states(&con, 1100), vec![(500, true, false)]);
+        // Standing still, and not cleared however long it's paused.
+        assert_eq!(states(&con, 9000), vec![(500, true, false)]);
+        // A paused timer isn't started again.
+        start_timer(&con, &recipe, 1, 0, 9000).unwrap();
+        assert_eq!(states(&con, 9000).len(), 1);
+        // Pausing twice keeps the first pause, another recipe can't touch it.
+        pause_timer(&con, &key(id), 9000).unwrap();
+        let other_recipe = TimerKey {
+            recipe_id: 2,
+            ..key(id)
+        };
+        resume_timer(&con, &other_recipe, 9000).unwrap();
+        assert_eq!(states(&con, 9000), vec![(500, true, false)]);
+
+        resume_timer(&con, &key(id), 9000).unwrap();
+        assert_eq!(states(&con, 9000), vec![(500, false, false)]);
+        assert_eq!(states(&con, 9400), vec![(100, false, false)]);
+        resume_timer(&con, &key(id), 9400).unwrap();
+        assert_eq!(states(&con, 9400), vec![(100, false, false)]);
+    }
+
+    #[test]
+    fn finishes_and_clears_timers() {
+        let (con, recipe) = setup();
+        start_timer(&con, &recipe, 1, 0, 1000).unwrap();
+        let id = get_timers(&con, 1, 1, 1000).unwrap()[0].id;
+        assert_eq!(states(&con, 1600), vec![(0, false, true)]);
+        // A finished timer can't be paused any more.
+        pause_timer(&con, &key(id), 1700).unwrap();
+        assert_eq!(states(&con, 1700), vec![(0, false, true)]);
+        assert_eq!(states(&con, 1600 + KEEP_FINISHED_SECONDS).len(), 1);
+        assert!(states(&con, 1601 + KEEP_FINISHED_SECONDS).is_empty());
+        // Left out while polling, but only deleted by the next start.
+        let stored = || -> usize {
+            con.query_row("SELECT count(*) FROM cook_timers", (), |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(stored(), 1);
+
+        start_timer(&con, &recipe, 1, 0, 6000).unwrap();
+        assert_eq!(stored(), 1);
+        let id = get_timers(&con, 1, 1, 6000).unwrap()[0].id;
+        let other_recipe = TimerKey {
+            recipe_id: 2,
+            ..key(id)
+        };
+        cancel_timer(&con, &other_recipe, 6000).unwrap();
+        assert_eq!(states(&con, 6000).len(), 1);
+        cancel_timer(&con, &key(id), 6000).unwrap();
+        assert!(states(&con, 6000).is_empty());
+    }
+
+    #[test]
+    fn answers_polling_with_every_timer() {
+        let (con, recipe) = setup();
+        start_timer(&con, &recipe, 1, 0, 1000).unwrap();
+        let id = get_timers(&con, 1, 1, 1000).unwrap()[0].id;
+        pause_timer(&con, &key(id), 1030).unwrap();
+        let polled = serde_json::to_value(get_timers(&con, 1, 1, 1200).unwrap()).unwrap();
+        assert_eq!(
+            polled,
+            serde_json::json!([{
+                "id": id,
+                "step": 0,
+                "label": "Step 1",
+                "duration_seconds": 600,
+                "remaining_seconds": 570,
+                "paused": true,
+                "finished": false,
+            }])
+        );
+        assert_eq!(
+            serde_json::to_value(get_timers(&con, 2, 1, 1200).unwrap()).unwrap(),
+            serde_json::json!([])
+        );
+    }
+
+    #[test]
+    fn keeps_each_users_timers_to_themselves() {
+        let (con, recipe) = setup();
+        start_timer(&con, &recipe, 1, 0, 1000).unwrap();
+        let id = get_timers(&con, 1, 1, 1000).unwrap()[0].id;
+        // Someone else cooking the same recipe starts their own timer, and
+        // can't pause or cancel ours.
+        assert!(get_timers(&con, 1, 2, 1000).unwrap().is_empty());
+        start_timer(&con, &recipe, 2, 0, 1100).unwrap();
+        let theirs = TimerKey {
+            user_id: 2,
+            ..key(id)
+        };
+        pause_timer(&con, &theirs, 1200).unwrap();
+        cancel_timer(&con, &theirs, 1200).unwrap();
+        assert_eq!(states(&con, 1200), vec![(400, false, false)]);
+        assert_eq!(
+            get_timers(&con, 1, 2, 1200)
+                .unwrap()
+                .iter()
+                .map(|t| t.remaining_seconds)
+                .collect::<Vec<u32>>(),
+            vec![500]
+        );
+    }
+}
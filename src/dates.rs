@@ -0,0 +1,129 @@
+// Calendar dates without a time zone, stored as `YYYY-MM-DD` text so they
+// sort correctly in SQLite. Day arithmetic uses the days-from-civil
+// algorithm from http://howardhinnant.github.io/date_algorithms.html.
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Seconds since the Unix epoch.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Date {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    pub fn parse(text: &str) -> Option<Date> {
+        let mut parts = text.trim().splitn(3, '-');
+        let year = parts.next()?.parse::<i64>().ok()?;
+        let month = parts.next()?.parse::<u32>().ok()?;
+        let day = parts.next()?.parse::<u32>().ok()?;
+        let date = Date { year, month, day };
+        // Rejects e.g. 2023-02-30 by checking it survives a round trip.
+        match (1..=12).contains(&month) && day >= 1 && Date::from_days(date.days()) == date {
+            true => Some(date),
+            false => None,
+        }
+    }
+
+    // Today in UTC.
+    pub fn today() -> Date {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Date::from_days((seconds / 86_400) as i64)
+    }
+
+    // Days since 1970-01-01.
+    pub fn days(&self) -> i64 {
+        let year = if self.month <= 2 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year =
+            (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146_097 + day_of_era - 719_468
+    }
+
+    pub fn from_days(days: i64) -> Date {
+        let days = days + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        Date { year, month, day }
+    }
+
+    pub fn add_days(&self, days: i64) -> Date {
+        Date::from_days(self.days() + days)
+    }
+
+    // 0 is Monday, 6 is Sunday. 1970-01-01 was a Thursday.
+    pub fn weekday(&self) -> i64 {
+        (self.days() + 3).rem_euclid(7)
+    }
+
+    // The Monday on or before this date.
+    pub fn week_start(&self) -> Date {
+        self.add_days(-self.weekday())
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_to_and_from_days() {
+        let epoch = Date::parse("1970-01-01").unwrap();
+        assert_eq!(epoch.days(), 0);
+        assert_eq!(Date::from_days(0), epoch);
+        let leap = Date::parse("2024-02-29").unwrap();
+        assert_eq!(leap.add_days(1).to_string(), "2024-03-01");
+        assert_eq!(leap.add_days(-60).to_string(), "2023-12-31");
+        assert_eq!(Date::from_days(leap.days()), leap);
+    }
+
+    #[test]
+    fn finds_weekdays() {
+        let thursday = Date::parse("1970-01-01").unwrap();
+        assert_eq!(thursday.weekday(), 3);
+        let sunday = Date::parse("2024-03-03").unwrap();
+        assert_eq!(sunday.weekday(), 6);
+        assert_eq!(sunday.week_start().to_string(), "2024-02-26");
+        assert_eq!(Date::parse("1969-12-29").unwrap().weekday(), 0);
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        assert_eq!(Date::parse("2023-02-29"), None);
+        assert_eq!(Date::parse("2023-13-01"), None);
+        assert_eq!(Date::parse("2023-01-00"), None);
+        assert_eq!(Date::parse("tomorrow"), None);
+    }
+}
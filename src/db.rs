@@ -0,0 +1,144 @@
+// Connections to main.db come from a pool shared by all threads, so a
+// request doesn't pay for opening the database, and the handful of queries
+// one request makes reuse the same few connections. The database is in WAL
+// mode: readers don't block the one writer or each other, and a writer
+// waits for another one to finish instead of failing.
+
+use r2d2::{ManageConnection, Pool, PooledConnection};
+use rusqlite::{ffi, Connection, Transaction, TransactionBehavior};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+#[cfg(not(test))]
+fn path() -> PathBuf {
+    PathBuf::from("main.db")
+}
+
+// Tests get a database of their own, which is shared by all tests of one
+// run, so they shouldn't rely on being the only ones writing to it.
+#[cfg(test)]
+fn path() -> PathBuf {
+    std::env::temp_dir().join(format!("recipe-helper-test-{}.db", std::process::id()))
+}
+
+// Every worker thread can hold a few connections at once, e.g. one for a
+// transaction and one for a lookup made inside it.
+pub const POOL_SIZE: u32 = 32;
+// How long a writer waits for another one.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct SqliteManager;
+
+impl ManageConnection for SqliteManager {
+    type Connection = Connection;
+    type Error = rusqlite::Error;
+
+    fn connect(&self) -> rusqlite::Result<Connection> {
+        let con = Connection::open(path())?;
+        con.busy_timeout(BUSY_TIMEOUT)?;
+        // Answers with the mode it ended up in, so it can't go through
+        // `pragma_update`.
+        con.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+        // Safe with WAL, only the last commits can be lost on power loss.
+        con.pragma_update(None, "synchronous", "NORMAL")?;
+        Ok(con)
+    }
+
+    fn is_valid(&self, con: &mut Connection) -> rusqlite::Result<()> {
+        con.execute_batch("")
+    }
+
+    fn has_broken(&self, _: &mut Connection) -> bool {
+        false
+    }
+}
+
+pub type PooledCon = PooledConnection<SqliteManager>;
+
+fn pool() -> &'static Pool<SqliteManager> {
+    static POOL: OnceLock<Pool<SqliteManager>> = OnceLock::new();
+    POOL.get_or_init(|| {
+        #[cfg(test)]
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+        let pool = Pool::builder()
+            .max_size(POOL_SIZE)
+            .min_idle(Some(4))
+            .connection_timeout(Duration::from_secs(10))
+            .build_unchecked(SqliteManager);
+        #[cfg(test)]
+        crate::migrations::migrate(&mut pool.get().expect("To open the test database"))
+            .expect("To migrate the test database");
+        pool
+    })
+}
+
+// A connection from the pool. A pool that stays empty for too long, or a
+// database that can't be opened, is reported like SQLite being busy.
+pub fn connection() -> rusqlite::Result<PooledCon> {
+    pool().get().map_err(|e| {
+        rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_BUSY), Some(e.to_string()))
+    })
+}
+
+// A transaction that writes. It takes the write lock when it starts, so two
+// of them wait for each other rather than both reading and then failing
+// when one can't upgrade to writing.
+pub fn write_transaction(con: &mut Connection) -> rusqlite::Result<Transaction<'_>> {
+    con.transaction_with_behavior(TransactionBehavior::Immediate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn pooled_connections_use_wal() {
+        let cons = (0..3).map(|_| connection().unwrap()).collect::<Vec<_>>();
+        for con in cons.iter() {
+            let mode: String = con
+                .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(mode, "wal");
+        }
+    }
+
+    #[test]
+    fn writers_wait_for_each_other() {
+        connection()
+            .unwrap()
+            .execute_batch(
+                "create table counter (n integer not null);
+                 insert into counter (n) values (0);",
+            )
+            .unwrap();
+        // Each one reads the counter before writing it, which fails or
+        // loses an update unless the transactions take turns.
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..10 {
+                        let mut con = connection().unwrap();
+                        let tx = write_transaction(&mut con).unwrap();
+                        let n: usize = tx
+                            .query_row("select n from counter", [], |row| row.get(0))
+                            .unwrap();
+                        thread::sleep(Duration::from_millis(1));
+                        tx.execute("update counter set n = ?", [n + 1]).unwrap();
+                        tx.commit().unwrap();
+                    }
+                });
+            }
+        });
+        let n: usize = connection()
+            .unwrap()
+            .query_row("select n from counter", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(n, 80);
+    }
+}
@@ -0,0 +1,151 @@
+// What can go wrong while answering a request. Handlers return
+// `Result<ResponseBox>` and pass errors on with `?`; `serve` turns them into
+// an error page, or JSON for the API, with a matching status. Database and
+// IO errors are logged and only shown as "something went wrong". A handler
+// that panics anyway gets a 500 as well, and the server goes on with the
+// next request.
+
+use crate::template::Template;
+use serde::Serialize;
+use std::{fmt, io};
+use tiny_http::{Header, Response, ResponseBox};
+
+#[derive(Debug)]
+pub enum AppError {
+    Db(rusqlite::Error),
+    Io(io::Error),
+    // A request we can't read, e.g. a body that isn't UTF-8.
+    Parse(String),
+    NotFound(String),
+    // Input the user can fix and send again.
+    Validation(String),
+    // Not allowed for this user, or a form without its CSRF token.
+    Forbidden(String),
+    // A body over the size limit.
+    TooLarge(String),
+}
+
+pub type Result<T> = std::result::Result<T, AppError>;
+
+impl AppError {
+    pub fn status(&self) -> u16 {
+        match self {
+            AppError::Db(_) | AppError::Io(_) => 500,
+            AppError::Parse(_) | AppError::Validation(_) => 400,
+            AppError::NotFound(_) => 404,
+            AppError::Forbidden(_) => 403,
+            AppError::TooLarge(_) => 413,
+        }
+    }
+
+    // What the user gets to see.
+    pub fn message(&self) -> String {
+        match self {
+            AppError::Db(_) | AppError::Io(_) => {
+                "Something went wrong on our side. Please try again.".to_string()
+            }
+            AppError::Parse(message)
+            | AppError::NotFound(message)
+            | AppError::Validation(message)
+            | AppError::Forbidden(message)
+            | AppError::TooLarge(message) => message.clone(),
+        }
+    }
+
+    pub fn into_response(self, url: &str) -> ResponseBox {
+        error_response(url, self.status(), &self.message())
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppError::Db(e) => write!(f, "database: {}", e),
+            AppError::Io(e) => write!(f, "io: {}", e),
+            AppError::Parse(message) => write!(f, "can't read the request: {}", message),
+            AppError::NotFound(message)
+            | AppError::Validation(message)
+            | AppError::Forbidden(message)
+            | AppError::TooLarge(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(e: rusqlite::Error) -> AppError {
+        AppError::Db(e)
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(e: io::Error) -> AppError {
+        AppError::Io(e)
+    }
+}
+
+#[derive(Serialize)]
+pub struct ApiError<'a> {
+    pub error: &'a str,
+}
+
+// `{"error": "..."}` under /api/, a page otherwise.
+pub fn error_response(url: &str, status: u16, message: &str) -> ResponseBox {
+    let (body, content_type) = match url.starts_with("/api/") {
+        true => (
+            serde_json::to_string(&ApiError { error: message }).expect("To serialize the error"),
+            "application/json",
+        ),
+        false => {
+            let mut page = Template::page("error.html");
+            page.set("title", title(status));
+            page.set("message", message);
+            (page.render(), "text/html; charset=utf-8")
+        }
+    };
+    Response::from_string(body)
+        .with_status_code(status)
+        .with_header(
+            Header::from_bytes("Content-Type", content_type)
+                .expect("That we didn't put any garbage in the headers"),
+        )
+        .boxed()
+}
+
+fn title(status: u16) -> &'static str {
+    match status {
+        400 => "That didn't work",
+        403 => "Not allowed",
+        404 => "Not found",
+        413 => "Too large",
+        _ => "Something went wrong",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn hides_internal_details() {
+        let error = AppError::from(rusqlite::Error::QueryReturnedNoRows);
+        assert_eq!(error.status(), 500);
+        assert!(!error.message().contains("no rows"));
+        assert!(error.to_string().contains("no rows"));
+
+        let error = AppError::Validation("A recipe needs a name.".to_string());
+        assert_eq!(error.status(), 400);
+        assert_eq!(error.message(), "A recipe needs a name.");
+    }
+
+    #[test]
+    fn answers_the_api_in_json() {
+        let mut body = String::new();
+        let response = error_response("/api/v1/recipes/9", 404, "Recipe not found");
+        assert_eq!(response.status_code().0, 404);
+        response.into_reader().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "{\"error\":\"Recipe not found\"}");
+    }
+}
@@ -0,0 +1,264 @@
+// Request bodies sent by forms, urlencoded or multipart/form-data, and
+// query strings. Every field keeps all of its values in the order they were
+// sent, so a repeated field such as one `ingredient` per row comes back as
+// a list. Bodies are read up to a limit and anything bigger is refused.
+
+use crate::errors::{AppError, Result};
+use crate::{find_header, unreadable_form};
+use std::io::Read;
+use tiny_http::Request;
+
+// Urlencoded forms and API bodies are only ever typed in.
+pub const FORM_MAX: usize = 1024 * 1024;
+// Uploads are saved web pages.
+pub const UPLOAD_MAX: usize = 10 * 1024 * 1024;
+
+#[derive(Default, Debug)]
+pub struct Form {
+    fields: Vec<(String, String)>,
+    files: Vec<UploadedFile>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UploadedFile {
+    pub field: String,
+    pub filename: String,
+    pub data: Vec<u8>,
+}
+
+impl Form {
+    // The first value of a field.
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value)
+    }
+
+    // Every value of a field, e.g. one per row of a table.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.fields
+            .iter()
+            .filter(|(field, _)| field == name)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    // All fields in the order they were sent.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(field, value)| (field.as_str(), value.as_str()))
+    }
+
+    pub fn file(&self, name: &str) -> Option<&UploadedFile> {
+        self.files.iter().find(|file| file.field == name)
+    }
+}
+
+// The body, refusing anything bigger than `max` bytes. A Content-Length
+// that's too big is refused before reading anything.
+pub fn read_body(request: &mut Request, max: usize) -> Result<Vec<u8>> {
+    let too_large = || {
+        AppError::TooLarge(format!(
+            "That's too much to send at once, the limit is {} KB.",
+            max / 1024
+        ))
+    };
+    if request.body_length().is_some_and(|length| length > max) {
+        return Err(too_large());
+    }
+    let mut body = vec![];
+    request
+        .as_reader()
+        .take(max as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(unreadable_form)?;
+    if body.len() > max {
+        return Err(too_large());
+    }
+    Ok(body)
+}
+
+// The form in the request body, urlencoded or multipart.
+pub fn read_form(request: &mut Request) -> Result<Form> {
+    let content_type = find_header(request.headers(), "Content-Type".to_string())
+        .map(|header| header.value.to_string())
+        .unwrap_or_default();
+    match multipart_boundary(&content_type) {
+        Some(boundary) => {
+            let body = read_body(request, UPLOAD_MAX)?;
+            parse_multipart(&body, &boundary)
+        }
+        None => {
+            let body = read_body(request, FORM_MAX)?;
+            let body = String::from_utf8(body).map_err(unreadable_form)?;
+            parse_urlencoded(&body)
+        }
+    }
+}
+
+// `a=1&b=x+y&a=2`, as sent by a form or in a query string. A `+` is a
+// space, and a plus sign comes as `%2B`.
+pub fn parse_urlencoded(text: &str) -> Result<Form> {
+    let decode = |part: &str| {
+        urlencoding::decode(&part.replace('+', " "))
+            .map(|decoded| decoded.into_owned())
+            .map_err(unreadable_form)
+    };
+    let fields = text
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((decode(name)?, decode(value)?))
+        })
+        .collect::<Result<Vec<(String, String)>>>()?;
+    Ok(Form {
+        fields,
+        files: vec![],
+    })
+}
+
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    let mut parts = content_type.split(';');
+    if !parts
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+    parts
+        .find_map(|part| part.trim().strip_prefix("boundary="))
+        .map(|boundary| boundary.trim_matches('"').to_string())
+        .filter(|boundary| !boundary.is_empty())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+// A parameter of a Content-Disposition header, e.g. `name` in
+// `form-data; name="file"; filename="soup.html"`.
+fn disposition_param(disposition: &str, param: &str) -> Option<String> {
+    disposition.split(';').skip(1).find_map(|part| {
+        let (key, value) = part.trim().split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case(param)
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+fn parse_multipart(body: &[u8], boundary: &str) -> Result<Form> {
+    let broken = || AppError::Parse("The upload couldn't be read.".to_string());
+    // Every part is preceded by a line break and the boundary, the first one
+    // too once the preamble is skipped.
+    let delimiter = format!("\r\n--{}", boundary).into_bytes();
+    let mut rest = [b"\r\n".as_slice(), body].concat();
+    let start = find(&rest, &delimiter).ok_or_else(broken)?;
+    rest.drain(..start + delimiter.len());
+
+    let mut form = Form::default();
+    loop {
+        if rest.starts_with(b"--") {
+            return Ok(form);
+        }
+        let headers_start = find(&rest, b"\r\n").ok_or_else(broken)? + 2;
+        let headers_end = find(&rest, b"\r\n\r\n").ok_or_else(broken)?;
+        let end = find(&rest, &delimiter).ok_or_else(broken)?;
+        if headers_end + 4 > end {
+            return Err(broken());
+        }
+        let headers = String::from_utf8_lossy(&rest[headers_start.min(headers_end)..headers_end]);
+        let disposition = headers
+            .split("\r\n")
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Disposition"))
+            .map(|(_, value)| value.trim().to_string())
+            .ok_or_else(broken)?;
+        let name = disposition_param(&disposition, "name").ok_or_else(broken)?;
+        let content = rest[headers_end + 4..end].to_vec();
+        match disposition_param(&disposition, "filename") {
+            Some(filename) => form.files.push(UploadedFile {
+                field: name,
+                filename,
+                data: content,
+            }),
+            None => form
+                .fields
+                .push((name, String::from_utf8(content).map_err(unreadable_form)?)),
+        }
+        rest.drain(..end + delimiter.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_urlencoded_forms() {
+        let form = parse_urlencoded("name=Mac+%26+cheese&note=a%2Bb%3Dc&q=1=2&flag&&x=").unwrap();
+        assert_eq!(
+            form.fields,
+            vec![
+                ("name".to_string(), "Mac & cheese".to_string()),
+                ("note".to_string(), "a+b=c".to_string()),
+                ("q".to_string(), "1=2".to_string()),
+                ("flag".to_string(), "".to_string()),
+                ("x".to_string(), "".to_string()),
+            ]
+        );
+        assert!(parse_urlencoded("name=%FF").is_err());
+    }
+
+    #[test]
+    fn keeps_repeated_fields_apart() {
+        let form = parse_urlencoded("ingredient=salt&ingredient=a%7Cb&ingredient=").unwrap();
+        assert_eq!(form.get("ingredient").unwrap(), "salt");
+        assert_eq!(form.get_all("ingredient"), vec!["salt", "a|b", ""]);
+        assert!(form.get("unit").is_none());
+        assert!(form.get_all("unit").is_empty());
+    }
+
+    #[test]
+    fn reads_multipart_forms() {
+        assert_eq!(
+            multipart_boundary("multipart/form-data; boundary=\"xyz\"").as_deref(),
+            Some("xyz")
+        );
+        assert_eq!(
+            multipart_boundary("application/x-www-form-urlencoded"),
+            None
+        );
+        let body = b"preamble\r\n--xyz\r\n\
+            Content-Disposition: form-data; name=\"csrf_token\"\r\n\r\n\
+            abc\r\n--xyz\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"soup.html\"\r\n\
+            Content-Type: text/html\r\n\r\n\
+            <p>a\r\n--xy</p>\r\n--xyz--\r\n";
+        let form = parse_multipart(body, "xyz").unwrap();
+        assert_eq!(form.get("csrf_token").unwrap(), "abc");
+        assert_eq!(
+            form.file("file"),
+            Some(&UploadedFile {
+                field: "file".to_string(),
+                filename: "soup.html".to_string(),
+                data: b"<p>a\r\n--xy</p>".to_vec(),
+            })
+        );
+        assert!(parse_multipart(
+            b"--xyz\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1",
+            "xyz"
+        )
+        .is_err());
+        assert!(parse_multipart(b"no boundary here", "xyz").is_err());
+    }
+}
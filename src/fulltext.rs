@@ -0,0 +1,181 @@
+// Full-text search over recipe names, descriptions, steps and ingredient
+// names, backed by the `recipes_fts` FTS5 table. The `description` column
+// holds the description and the steps. The table's rowid is the recipe id;
+// it has to be updated whenever a recipe or its ingredients change.
+
+use crate::users::User;
+use crate::{escape_html, sharing, RecipeShort};
+use rusqlite::{named_params, Connection};
+use serde::Serialize;
+
+// Highlight markers used by `snippet()`. Control characters don't show
+// up in recipe text and survive escaping, so they are swapped for `<mark>`
+// afterwards.
+const MARK_START: &str = "\u{2}";
+const MARK_END: &str = "\u{3}";
+
+#[derive(Serialize)]
+pub struct TextResult {
+    pub recipe: RecipeShort,
+    // HTML with matches wrapped in `<mark>`.
+    pub snippet: String,
+}
+
+impl TextResult {
+    pub fn render_link(self) -> String {
+        let mut link = self.recipe.render_link();
+        let snippet = format!("<div class=\"snippet\">{}</div>", self.snippet);
+        link = link.replace("</div>", snippet.as_str());
+        link += "</div>";
+        link
+    }
+}
+
+// Rewrites the recipe's row from the current state of the database.
+pub fn index_recipe(con: &Connection, recipe_id: usize) -> rusqlite::Result<()> {
+    remove_recipe(con, recipe_id)?;
+    con.execute(
+        "INSERT INTO recipes_fts (rowid, name, description, ingredients)
+         SELECT r.id, r.name,
+            trim(coalesce(r.description, '') || ' ' ||
+                coalesce((SELECT group_concat(s.text, ' ')
+                    FROM recipe_steps AS s WHERE s.recipe_id = r.id), '')),
+            coalesce((SELECT group_concat(i.name, ' ')
+                FROM recipe_ingredients AS ri
+                JOIN ingredients AS i ON i.id = ri.ingredient_id
+                WHERE ri.recipe_id = r.id), '')
+         FROM recipes AS r WHERE r.id = :id",
+        named_params! { ":id": recipe_id },
+    )?;
+    Ok(())
+}
+
+pub fn remove_recipe(con: &Connection, recipe_id: usize) -> rusqlite::Result<()> {
+    con.execute(
+        "DELETE FROM recipes_fts WHERE rowid = :id",
+        named_params! { ":id": recipe_id },
+    )?;
+    Ok(())
+}
+
+// Turns what the user typed into an FTS5 query: every word has to match,
+// as a prefix, and nothing is interpreted as FTS syntax.
+fn fts_query(text: &str) -> Option<String> {
+    let terms = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"*", word))
+        .collect::<Vec<String>>();
+    if terms.is_empty() {
+        return None;
+    }
+    Some(terms.join(" "))
+}
+
+fn highlight(snippet: &str) -> String {
+    escape_html(snippet)
+        .replace(MARK_START, "<mark>")
+        .replace(MARK_END, "</mark>")
+}
+
+// Best matches first. Matches in the name count the most, then
+// ingredients, then the description.
+// Only recipes the user can see are found.
+pub fn search(con: &Connection, text: &str, user: &User) -> rusqlite::Result<Vec<TextResult>> {
+    let Some(query) = fts_query(text) else {
+        return Ok(vec![]);
+    };
+    let mut stmt = con.prepare(&format!(
+        "SELECT r.id, r.name, snippet(recipes_fts, -1, :start, :end, '…', 12)
+             FROM recipes_fts
+             JOIN recipes AS r ON r.id = recipes_fts.rowid
+             WHERE recipes_fts MATCH :query AND {}
+             ORDER BY bm25(recipes_fts, 10.0, 1.0, 5.0)
+             LIMIT 50",
+        sharing::VISIBLE
+    ))?;
+    let results = stmt
+        .query_map(
+            named_params! {
+                ":query": query,
+                ":start": MARK_START,
+                ":end": MARK_END,
+                ":user_id": user.id,
+            },
+            |row| {
+                Ok(TextResult {
+                    recipe: RecipeShort {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                    },
+                    snippet: highlight(row.get::<_, String>(2)?.as_str()),
+                })
+            },
+        )?
+        .collect();
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::migrate;
+
+    #[test]
+    fn builds_prefix_queries_without_fts_syntax() {
+        assert_eq!(
+            fts_query("choc cake"),
+            Some("\"choc\"* \"cake\"*".to_string())
+        );
+        assert_eq!(
+            fts_query("\"a\" OR name:b*"),
+            Some("\"a\"* \"OR\"* \"name\"* \"b\"*".to_string())
+        );
+        assert_eq!(fts_query(" -*- "), None);
+    }
+
+    #[test]
+    fn finds_and_highlights_recipes() {
+        let mut con = Connection::open_in_memory().unwrap();
+        migrate(&mut con).unwrap();
+        con.execute_batch(
+            "insert into users (id, name, password_hash) values (1, 'ada', ''), (2, 'bob', '');
+             insert into recipes (id, name, description, owner_id) values
+                (1, 'Chocolate cake', 'Bake <b>slowly</b>', null),
+                (2, 'Bread', 'Good with chocolate spread', null),
+                (3, 'Chocolate secret', 'Bob''s own', 2);
+             insert into ingredients (id, name) values (1, 'cocoa');
+             insert into recipe_ingredients (recipe_id, ingredient_id) values (1, 1);",
+        )
+        .unwrap();
+        index_recipe(&con, 1).unwrap();
+        index_recipe(&con, 2).unwrap();
+        index_recipe(&con, 3).unwrap();
+        let ada = User {
+            id: 1,
+            name: "ada".to_string(),
+        };
+
+        let results = search(&con, "choc", &ada).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].recipe.id, 1);
+        assert_eq!(results[0].snippet, "<mark>Chocolate</mark> cake");
+
+        let results = search(&con, "slow", &ada).unwrap();
+        assert_eq!(
+            results[0].snippet,
+            "Bake &lt;b&gt;<mark>slowly</mark>&lt;/b&gt;"
+        );
+
+        assert_eq!(search(&con, "cocoa", &ada).unwrap()[0].recipe.id, 1);
+
+        let bob = User {
+            id: 2,
+            name: "bob".to_string(),
+        };
+        assert_eq!(search(&con, "choc", &bob).unwrap().len(), 3);
+
+        remove_recipe(&con, 1).unwrap();
+        assert_eq!(search(&con, "cocoa", &ada).unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,546 @@
+// Imports recipes from saved web pages. Most recipe sites embed a
+// schema.org Recipe as JSON-LD; we take the name, ingredients,
+// instructions, yield and times from it. In the browser the result is shown
+// in the add form to check before saving, on the command line it's saved
+// right away:
+//
+//     recipe-helper import saved-page.html...
+
+use crate::archive::{self, Format};
+use crate::db;
+use crate::errors::Result;
+use crate::forms;
+use crate::migrations;
+use crate::sharing::Sharing;
+use crate::units::{is_unit, parse_quantity};
+use crate::users::User;
+use crate::{
+    add_page, escape_html, get_con, recipe_id_by_name, resolve_ingredient_lines, serve_html,
+    template::Template, Ingredient, IngredientLine, Recipe, RecipeIngredient, Step,
+};
+use regex::Regex;
+use serde_json::Value;
+use std::fs;
+use tiny_http::{Request, ResponseBox};
+
+// Amounts that aren't convertible but are still worth keeping apart from
+// the ingredient name.
+const OTHER_UNITS: &[&str] = &[
+    "bunch", "bunches", "can", "cans", "clove", "cloves", "handful", "handfuls", "package",
+    "packages", "pinch", "pinches", "slice", "slices", "sprig", "sprigs", "stick", "sticks",
+];
+
+const FRACTIONS: &[(char, &str)] = &[
+    ('½', "1/2"),
+    ('⅓', "1/3"),
+    ('⅔', "2/3"),
+    ('¼', "1/4"),
+    ('¾', "3/4"),
+    ('⅛', "1/8"),
+];
+
+// A recipe as found in the page, before anything is saved.
+struct Draft {
+    name: String,
+    description: Option<String>,
+    steps: Vec<String>,
+    servings: Option<u32>,
+    prep_minutes: Option<u32>,
+    cook_minutes: Option<u32>,
+    total_minutes: Option<u32>,
+    lines: Vec<IngredientLine>,
+}
+
+impl Draft {
+    // For the add form; ingredients aren't created until the form is saved.
+    fn preview(&self) -> Recipe {
+        let ingredients = self
+            .lines
+            .iter()
+            .map(|line| RecipeIngredient {
+                ingredient: Ingredient {
+                    id: 0,
+                    name: line.ingredient.clone(),
+                },
+                quantity: line.quantity,
+                unit: line.unit.clone(),
+                note: line.note.clone(),
+            })
+            .collect();
+        Recipe {
+            id: 0,
+            name: self.name.clone(),
+            ingredients,
+            description: self.description.clone(),
+            steps: self.steps.iter().map(|text| step(text)).collect(),
+            servings: self.servings,
+            prep_minutes: self.prep_minutes,
+            cook_minutes: self.cook_minutes,
+            total_minutes: self.total_minutes,
+            sharing: Sharing::default(),
+        }
+    }
+
+    fn create(self) -> Result<Recipe> {
+        let mut con = get_con()?;
+        let tx = db::write_transaction(&mut con)?;
+        let recipe = Recipe {
+            id: 0,
+            name: self.name,
+            ingredients: resolve_ingredient_lines(&tx, self.lines)?,
+            description: self.description,
+            steps: self.steps.iter().map(|text| step(text)).collect(),
+            servings: self.servings,
+            prep_minutes: self.prep_minutes,
+            cook_minutes: self.cook_minutes,
+            total_minutes: self.total_minutes,
+            sharing: Sharing::default(),
+        }
+        .insert(&tx)?;
+        tx.commit()?;
+        Ok(recipe)
+    }
+}
+
+fn step(text: &str) -> Step {
+    Step {
+        text: text.to_string(),
+        timer_seconds: None,
+        ingredients: vec![],
+    }
+}
+
+fn decode_entities(text: &str) -> String {
+    let re = Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").unwrap();
+    re.replace_all(text, |caps: &regex::Captures| {
+        let entity = &caps[1];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => match entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+            {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => entity
+                    .strip_prefix('#')
+                    .and_then(|d| d.parse().ok())
+                    .and_then(char::from_u32),
+            },
+        };
+        match decoded {
+            Some(c) => c.to_string(),
+            None => caps[0].to_string(),
+        }
+    })
+    .to_string()
+}
+
+// Plain text from a JSON-LD string, which sites often fill with HTML.
+// Paragraphs and list items become lines.
+fn clean_text(text: &str) -> String {
+    let breaks = Regex::new(r"(?i)<br\s*/?>|</p>|</li>").unwrap();
+    let tags = Regex::new(r"<[^>]*>").unwrap();
+    let text = breaks.replace_all(text, "\n");
+    let text = decode_entities(&tags.replace_all(&text, ""));
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// A string, number, or the first of a list of them.
+fn value_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(clean_text(text)),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Array(items) => items.iter().find_map(value_text),
+        Value::Object(object) => object
+            .get("text")
+            .or(object.get("@value"))?
+            .as_str()
+            .map(clean_text),
+        _ => None,
+    }
+    .filter(|text| !text.is_empty())
+}
+
+fn is_recipe(value: &Value) -> bool {
+    let is_recipe_type = |t: &Value| {
+        t.as_str()
+            .is_some_and(|t| t == "Recipe" || t.ends_with("/Recipe"))
+    };
+    match value.get("@type") {
+        Some(Value::Array(types)) => types.iter().any(is_recipe_type),
+        Some(t) => is_recipe_type(t),
+        None => false,
+    }
+}
+
+// The Recipe may be the whole document, in a list or in an `@graph`.
+fn find_recipe(value: &Value) -> Option<&Value> {
+    match value {
+        Value::Array(items) => items.iter().find_map(find_recipe),
+        Value::Object(_) if is_recipe(value) => Some(value),
+        Value::Object(object) => ["@graph", "mainEntity"]
+            .iter()
+            .find_map(|key| object.get(*key).and_then(find_recipe)),
+        _ => None,
+    }
+}
+
+// Instructions are a string, a list of strings or HowToSteps, or
+// HowToSections of those.
+fn collect_steps(value: &Value, steps: &mut Vec<String>) {
+    match value {
+        Value::String(text) => steps.extend(clean_text(text).lines().map(String::from)),
+        Value::Array(items) => {
+            for item in items {
+                collect_steps(item, steps);
+            }
+        }
+        Value::Object(object) => {
+            if let Some(items) = object.get("itemListElement") {
+                if let Some(name) = object.get("name").and_then(value_text) {
+                    steps.push(format!("{}:", name));
+                }
+                collect_steps(items, steps);
+            } else if let Some(text) = object.get("text").or(object.get("name")) {
+                collect_steps(text, steps);
+            }
+        }
+        _ => {}
+    }
+}
+
+// ISO 8601 durations such as "PT1H30M" or "P0DT45M", in minutes.
+fn parse_duration(text: &str) -> Option<u32> {
+    let text = text.trim().strip_prefix('P')?;
+    let (days, time) = text.split_once('T').unwrap_or((text, ""));
+    let mut seconds = 0.0;
+    if !days.is_empty() {
+        seconds += days.strip_suffix('D')?.parse::<f64>().ok()? * 86_400.0;
+    }
+    let mut number = String::new();
+    for c in time.chars() {
+        let size = match c {
+            'H' => 3600.0,
+            'M' => 60.0,
+            'S' => 1.0,
+            _ => {
+                number.push(c);
+                continue;
+            }
+        };
+        seconds += number.parse::<f64>().ok()? * size;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return None;
+    }
+    Some((seconds / 60.0).round() as u32)
+}
+
+// "4", "4 servings" or "Makes 12 muffins".
+fn parse_yield(value: &Value) -> Option<u32> {
+    let text = value_text(value)?;
+    let re = Regex::new(r"\d+").unwrap();
+    re.find(&text)?.as_str().parse().ok().filter(|s| *s > 0)
+}
+
+// Splits "1 ½ cups flour, sifted" into amount, unit, name and note.
+fn parse_ingredient(text: &str) -> Option<IngredientLine> {
+    let mut text = clean_text(text);
+    for (fraction, replacement) in FRACTIONS {
+        text = text.replace(*fraction, format!(" {}", replacement).as_str());
+    }
+    // "200g" is "200 g".
+    let attached = Regex::new(r"^(\d+(?:[.,]\d+)?)([^\d\s./,]+)(\s|$)").unwrap();
+    let text = attached.replace(text.trim(), "$1 $2$3").to_string();
+    let words = text.split_whitespace().collect::<Vec<&str>>();
+    if words.is_empty() {
+        return None;
+    }
+
+    let mut rest = &words[..];
+    let mut quantity = None;
+    for count in [2, 1] {
+        if words.len() > count {
+            if let Some(q) = parse_quantity(&words[..count].join(" ")) {
+                quantity = Some(q);
+                rest = &words[count..];
+                break;
+            }
+        }
+    }
+    let mut unit = None;
+    if quantity.is_some() {
+        for count in [2, 1] {
+            if rest.len() > count {
+                let candidate = rest[..count].join(" ");
+                let bare = candidate.trim_end_matches('.');
+                if is_unit(bare) || OTHER_UNITS.contains(&bare.to_lowercase().as_str()) {
+                    unit = Some(bare.to_string());
+                    rest = &rest[count..];
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut name = rest.join(" ");
+    if let Some(stripped) = name.strip_prefix("of ") {
+        name = stripped.to_string();
+    }
+    let mut notes = vec![];
+    if let Some((before, after)) = name.split_once(',') {
+        notes.push(after.trim().to_string());
+        name = before.to_string();
+    }
+    let parenthetical = Regex::new(r"\s*\(([^)]*)\)").unwrap();
+    for caps in parenthetical.captures_iter(&name) {
+        notes.insert(0, caps[1].trim().to_string());
+    }
+    let name = parenthetical.replace_all(&name, "").trim().to_string();
+    if name.is_empty() {
+        return None;
+    }
+    let note = notes
+        .into_iter()
+        .filter(|n| !n.is_empty())
+        .collect::<Vec<String>>()
+        .join(", ");
+    Some(IngredientLine {
+        ingredient: name,
+        quantity,
+        unit,
+        note: Some(note).filter(|n| !n.is_empty()),
+    })
+}
+
+fn extract_recipe(html: &str) -> Option<Draft> {
+    let scripts = Regex::new(
+        r#"(?is)<script[^>]*type\s*=\s*["']?application/ld\+json["']?[^>]*>(.*?)</script>"#,
+    )
+    .unwrap();
+    let documents = scripts
+        .captures_iter(html)
+        .filter_map(|caps| serde_json::from_str::<Value>(caps[1].trim()).ok())
+        .collect::<Vec<Value>>();
+    let recipe = documents.iter().find_map(find_recipe)?;
+
+    let name = recipe.get("name").and_then(value_text)?;
+    let mut lines = vec![];
+    if let Some(Value::Array(items)) = recipe.get("recipeIngredient").or(recipe.get("ingredients"))
+    {
+        lines = items
+            .iter()
+            .filter_map(|item| item.as_str())
+            .filter_map(parse_ingredient)
+            .collect();
+    }
+    let mut steps = vec![];
+    if let Some(instructions) = recipe.get("recipeInstructions") {
+        collect_steps(instructions, &mut steps);
+    }
+    let minutes = |key: &str| {
+        recipe
+            .get(key)
+            .and_then(|v| v.as_str())
+            .and_then(parse_duration)
+    };
+    Some(Draft {
+        name,
+        description: recipe.get("description").and_then(value_text),
+        steps,
+        servings: recipe.get("recipeYield").and_then(parse_yield),
+        prep_minutes: minutes("prepTime"),
+        cook_minutes: minutes("cookTime"),
+        total_minutes: minutes("totalTime"),
+        lines,
+    })
+}
+
+fn serve_import_page(request: &Request, error: &str) -> Result<ResponseBox> {
+    let mut page = Template::page("import.html");
+    let error = match error.is_empty() {
+        true => String::new(),
+        false => format!("<div class=\"import-error\">{}</div>", escape_html(error)),
+    };
+    page.set_html("error", error);
+    serve_html(request, page)
+}
+
+pub fn import_page(request: &Request) -> Result<ResponseBox> {
+    serve_import_page(request, "")
+}
+
+// Shows what was found in the add form, where it can be fixed up and saved.
+pub fn import_page_post(request: &mut Request, user: &User) -> Result<ResponseBox> {
+    let form = forms::read_form(request)?;
+    let Some(file) = form.file("file").filter(|file| !file.data.is_empty()) else {
+        return serve_import_page(request, "Choose a saved HTML file to import.");
+    };
+    let html = String::from_utf8_lossy(&file.data);
+    match extract_recipe(&html) {
+        Some(draft) => add_page(request, Some(draft.preview()), user),
+        None => serve_import_page(request, "No schema.org recipe found in this file."),
+    }
+}
+
+fn import_html(html: &str) -> std::result::Result<Recipe, String> {
+    let draft = extract_recipe(html).ok_or("no schema.org recipe found")?;
+    let con = get_con().map_err(|e| e.to_string())?;
+    if recipe_id_by_name(&con, &draft.name)
+        .map_err(|e| e.to_string())?
+        .is_some()
+    {
+        return Err(format!("a recipe named \"{}\" already exists", draft.name));
+    }
+    draft.create().map_err(|e| e.to_string())
+}
+
+// `recipe-helper import [--replace] FILE...`. JSON and YAML files are
+// archives from `recipe-helper export`, anything else a saved web page.
+pub fn import_files(args: &[String]) {
+    let replace = args.iter().any(|a| a == "--replace");
+    let paths = args
+        .iter()
+        .filter(|a| *a != "--replace")
+        .collect::<Vec<&String>>();
+    if paths.is_empty() {
+        eprintln!("usage: recipe-helper import [--replace] FILE.html|FILE.json|FILE.yaml...");
+        std::process::exit(2);
+    }
+    let migrated = get_con()
+        .map_err(migrations::MigrationError::from)
+        .and_then(|mut con| migrations::migrate(&mut con));
+    if let Err(e) = migrated {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+    let mut failed = false;
+    for path in paths {
+        let text = fs::read(path)
+            .map(|bytes| String::from_utf8_lossy(&bytes).to_string())
+            .map_err(|e| e.to_string());
+        let result = match Format::from_path(path) {
+            Some(format) => text.and_then(|text| archive::import(&text, format, replace)),
+            None => text
+                .and_then(|text| import_html(&text))
+                .map(|recipe| format!("imported \"{}\" as /recipe/{}", recipe.name, recipe.id)),
+        };
+        match result {
+            Ok(summary) => println!("{}: {}", path, summary),
+            Err(message) => {
+                eprintln!("{}: {}", path, message);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = r#"<html><head>
+        <script type="application/ld+json">{"@type": "WebSite", "name": "Cooking"}</script>
+        <script type="application/ld+json">
+        {"@context": "https://schema.org", "@graph": [
+            {"@type": "WebPage", "name": "Pancakes | Cooking"},
+            {"@type": ["Recipe"], "name": "Fluffy Pancakes &amp; Syrup",
+             "description": "<p>Sunday breakfast.</p>",
+             "recipeYield": ["4", "4 servings"],
+             "prepTime": "PT10M", "cookTime": "PT20M", "totalTime": "P0DT0H30M",
+             "recipeIngredient": ["1 ½ cups flour, sifted", "2 eggs", "200ml milk", "salt"],
+             "recipeInstructions": [
+                {"@type": "HowToSection", "name": "Batter", "itemListElement": [
+                    {"@type": "HowToStep", "text": "Mix everything."}
+                ]},
+                {"@type": "HowToStep", "text": "Fry in butter."}
+             ]}
+        ]}
+        </script></head></html>"#;
+
+    fn line(text: &str) -> (Option<f64>, Option<String>, String, Option<String>) {
+        let line = parse_ingredient(text).unwrap();
+        (line.quantity, line.unit, line.ingredient, line.note)
+    }
+
+    #[test]
+    fn extracts_recipe_from_json_ld() {
+        let draft = extract_recipe(PAGE).unwrap();
+        assert_eq!(draft.name, "Fluffy Pancakes & Syrup");
+        assert_eq!(draft.servings, Some(4));
+        assert_eq!(
+            (draft.prep_minutes, draft.cook_minutes, draft.total_minutes),
+            (Some(10), Some(20), Some(30))
+        );
+        assert_eq!(draft.description.as_deref(), Some("Sunday breakfast."));
+        assert_eq!(
+            draft.steps,
+            vec!["Batter:", "Mix everything.", "Fry in butter."]
+        );
+        let names = draft
+            .lines
+            .iter()
+            .map(|l| l.ingredient.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(names, vec!["flour", "eggs", "milk", "salt"]);
+        assert!(extract_recipe("<html>No recipe here</html>").is_none());
+    }
+
+    #[test]
+    fn parses_ingredient_lines() {
+        assert_eq!(
+            line("1 ½ cups flour, sifted"),
+            (
+                Some(1.5),
+                Some("cups".to_string()),
+                "flour".to_string(),
+                Some("sifted".to_string())
+            )
+        );
+        assert_eq!(
+            line("200g butter (softened)"),
+            (
+                Some(200.0),
+                Some("g".to_string()),
+                "butter".to_string(),
+                Some("softened".to_string())
+            )
+        );
+        assert_eq!(
+            line("2 cloves of garlic"),
+            (
+                Some(2.0),
+                Some("cloves".to_string()),
+                "garlic".to_string(),
+                None
+            )
+        );
+        assert_eq!(line("3 eggs"), (Some(3.0), None, "eggs".to_string(), None));
+        assert_eq!(
+            line("Salt &amp; pepper"),
+            (None, None, "Salt & pepper".to_string(), None)
+        );
+        assert!(parse_ingredient("  ").is_none());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("PT1H30M"), Some(90));
+        assert_eq!(parse_duration("PT45M30S"), Some(46));
+        assert_eq!(parse_duration("P1DT2H"), Some(1560));
+        assert_eq!(parse_duration("1 hour"), None);
+    }
+}
@@ -0,0 +1,265 @@
+// Renders recipe text written in CommonMark. Whatever the user typed ends
+// up escaped: raw HTML is shown as text, and only http(s), mailto and
+// relative URLs make it into links and images. Bare URLs in the text are
+// turned into links too, and single line breaks are kept, as people type
+// recipes line by line.
+
+use crate::escape_html;
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag, TagEnd};
+use regex::Regex;
+use std::sync::OnceLock;
+
+fn has_scheme(url: &str, schemes: &[&str]) -> bool {
+    let lower = url.to_lowercase();
+    schemes
+        .iter()
+        .any(|scheme| lower.starts_with(format!("{}:", scheme).as_str()))
+}
+
+// A URL is relative if there is no scheme before the first `/`, `?` or `#`.
+fn is_relative(url: &str) -> bool {
+    let end = url.find(['/', '?', '#']).unwrap_or(url.len());
+    !url[..end].contains(':')
+}
+
+fn safe_link(url: &str) -> bool {
+    has_scheme(url, &["http", "https", "mailto"]) || is_relative(url)
+}
+
+fn safe_image(url: &str) -> bool {
+    has_scheme(url, &["http", "https"]) || is_relative(url)
+}
+
+// Trailing punctuation belongs to the sentence, not the URL, and so does a
+// closing parenthesis that wasn't opened in the URL.
+fn trim_url(url: &str) -> &str {
+    let mut url = url;
+    loop {
+        let Some(last) = url.chars().last() else {
+            return url;
+        };
+        let unbalanced = |open: char| url.matches(open).count() < url.matches(last).count();
+        let trim = match last {
+            '.' | ',' | ';' | ':' | '!' | '?' | '\'' | '"' | '*' | '_' => true,
+            ')' => unbalanced('('),
+            ']' => unbalanced('['),
+            _ => false,
+        };
+        if !trim {
+            return url;
+        }
+        url = &url[..url.len() - last.len_utf8()];
+    }
+}
+
+// Links open in a new tab so the recipe stays open.
+fn link_start(url: &str, title: &str) -> String {
+    let title = match title.is_empty() {
+        true => String::new(),
+        false => format!(" title=\"{}\"", escape_html(title)),
+    };
+    format!(
+        "<a href=\"{}\"{} target=\"_blank\" rel=\"noopener\">",
+        escape_html(url),
+        title
+    )
+}
+
+fn link_html(url: &str, text: &str) -> String {
+    format!("{}{}</a>", link_start(url, ""), text)
+}
+
+// Escapes plain text and turns the http(s) URLs in it into links.
+fn linkify(text: &str) -> String {
+    static URL: OnceLock<Regex> = OnceLock::new();
+    let re = URL.get_or_init(|| Regex::new(r#"\bhttps?://[^\s<>"]+"#).unwrap());
+    let mut html = String::new();
+    let mut rest = 0;
+    for found in re.find_iter(text) {
+        let url = trim_url(found.as_str());
+        if !url.contains("://") || url.ends_with("://") {
+            continue;
+        }
+        html += escape_html(&text[rest..found.start()]).as_str();
+        html += link_html(url, &escape_html(url)).as_str();
+        rest = found.start() + url.len();
+    }
+    html += escape_html(&text[rest..]).as_str();
+    html
+}
+
+pub fn render(text: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TABLES);
+    let mut events: Vec<Event> = vec![];
+    // Text comes in pieces, e.g. split at `_`; URLs are found in the whole.
+    let mut pending = String::new();
+    // Whether each open link was kept, so its end is handled the same way.
+    let mut links: Vec<bool> = vec![];
+    let mut in_code_block = false;
+    // Image descriptions become the alt text, which must stay plain.
+    let mut images = 0;
+    for event in Parser::new_ext(text, options) {
+        if let Event::Text(text) = &event {
+            if links.is_empty() && !in_code_block && images == 0 {
+                pending += text;
+                continue;
+            }
+        }
+        if !pending.is_empty() {
+            events.push(Event::Html(CowStr::from(linkify(&pending))));
+            pending.clear();
+        }
+        let event = match event {
+            Event::Html(raw) | Event::InlineHtml(raw) => Event::Text(raw),
+            Event::SoftBreak => Event::HardBreak,
+            Event::Start(Tag::CodeBlock(kind)) => {
+                in_code_block = true;
+                Event::Start(Tag::CodeBlock(kind))
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                in_code_block = false;
+                Event::End(TagEnd::CodeBlock)
+            }
+            Event::Start(Tag::Link {
+                dest_url, title, ..
+            }) => {
+                let safe = safe_link(&dest_url);
+                links.push(safe);
+                if !safe {
+                    continue;
+                }
+                Event::Html(CowStr::from(link_start(&dest_url, &title)))
+            }
+            Event::End(TagEnd::Link) => match links.pop() {
+                Some(true) => Event::Html(CowStr::from("</a>")),
+                _ => continue,
+            },
+            Event::Start(Tag::Image {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => {
+                images += 1;
+                Event::Start(Tag::Image {
+                    link_type,
+                    dest_url: match safe_image(&dest_url) {
+                        true => dest_url,
+                        false => CowStr::from(""),
+                    },
+                    title,
+                    id,
+                })
+            }
+            Event::End(TagEnd::Image) => {
+                images -= 1;
+                Event::End(TagEnd::Image)
+            }
+            other => other,
+        };
+        events.push(event);
+    }
+    if !pending.is_empty() {
+        events.push(Event::Html(CowStr::from(linkify(&pending))));
+    }
+    let mut output = String::new();
+    html::push_html(&mut output, events.into_iter());
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(url: &str) -> String {
+        link_html(url, url)
+    }
+
+    #[test]
+    fn renders_commonmark() {
+        assert_eq!(
+            render("# Dough\n\n- **flour**\n- *water*"),
+            "<h1>Dough</h1>\n<ul>\n<li><strong>flour</strong></li>\n<li><em>water</em></li>\n</ul>\n"
+        );
+        assert_eq!(
+            render("Knead\nthen rest"),
+            "<p>Knead<br />\nthen rest</p>\n"
+        );
+        assert_eq!(
+            render("![cake](https://example.com/cake.jpg)"),
+            "<p><img src=\"https://example.com/cake.jpg\" alt=\"cake\" /></p>\n"
+        );
+        assert_eq!(
+            render("[the blog](https://example.com \"Blog\")"),
+            "<p><a href=\"https://example.com\" title=\"Blog\" target=\"_blank\" rel=\"noopener\">the blog</a></p>\n"
+        );
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            render("<script>alert(1)</script>"),
+            "&lt;script&gt;alert(1)&lt;/script&gt;"
+        );
+        assert_eq!(
+            render("Hot <img src=x onerror=alert(1)> pan"),
+            "<p>Hot &lt;img src=x onerror=alert(1)&gt; pan</p>\n"
+        );
+        assert_eq!(render("[click](javascript:alert(1))"), "<p>click</p>\n");
+        assert_eq!(
+            render("![x](data:text/html;base64,PHNjcmlwdD4=)"),
+            "<p><img src=\"\" alt=\"x\" /></p>\n"
+        );
+        assert_eq!(
+            render("`<b>` and\n\n    <i>"),
+            "<p><code>&lt;b&gt;</code> and</p>\n<pre><code>&lt;i&gt;</code></pre>\n"
+        );
+    }
+
+    #[test]
+    fn links_bare_urls() {
+        assert_eq!(
+            render("See https://example.com."),
+            format!("<p>See {}.</p>\n", link("https://example.com"))
+        );
+        assert_eq!(
+            render("From http://example.com/a, b"),
+            format!("<p>From {}, b</p>\n", link("http://example.com/a"))
+        );
+        assert_eq!(
+            render("(see https://example.com/pie)"),
+            format!("<p>(see {})</p>\n", link("https://example.com/pie"))
+        );
+        assert_eq!(
+            render("https://en.wikipedia.org/wiki/Pie_(dish)!"),
+            format!(
+                "<p>{}!</p>\n",
+                link("https://en.wikipedia.org/wiki/Pie_(dish)")
+            )
+        );
+        assert_eq!(
+            render("https://example.com/?a=1&b=2"),
+            format!(
+                "<p><a href=\"https://example.com/?a=1&amp;b=2\" target=\"_blank\" rel=\"noopener\">https://example.com/?a=1&amp;b=2</a></p>\n"
+            )
+        );
+        assert_eq!(
+            render("httpfoo://example.com"),
+            "<p>httpfoo://example.com</p>\n"
+        );
+        assert_eq!(
+            render("xhttps://example.com"),
+            "<p>xhttps://example.com</p>\n"
+        );
+        assert_eq!(render("http:// nothing"), "<p>http:// nothing</p>\n");
+        assert_eq!(
+            render("[named](https://example.com) https://example.org"),
+            format!(
+                "<p><a href=\"https://example.com\" target=\"_blank\" rel=\"noopener\">named</a> {}</p>\n",
+                link("https://example.org")
+            )
+        );
+    }
+}
@@ -0,0 +1,555 @@
+// Schema migrations. The number of migrations applied to a database is
+// kept in `PRAGMA user_version`; at startup every migration after that is
+// applied, in order, inside a single transaction.
+//
+// Migrations are append-only: never edit or reorder one that has shipped,
+// add a new one to the end of `MIGRATIONS` instead.
+
+use regex::Regex;
+use rusqlite::{Connection, Transaction};
+use std::fmt;
+
+type Migration = fn(&Transaction) -> rusqlite::Result<()>;
+
+const MIGRATIONS: &[Migration] = &[
+    baseline,
+    ingredient_amounts,
+    recipe_servings,
+    recipe_search_index,
+    pantry,
+    shopping_lists,
+    meal_plan,
+    recipe_times,
+    recipe_steps,
+    cook_timers,
+    users,
+    recipe_sharing,
+    session_csrf_tokens,
+    recipes_by_ingredient,
+    cook_timer_pauses,
+    cook_timer_users,
+    calendar_tokens,
+];
+
+#[derive(Debug)]
+pub enum MigrationError {
+    // The database was written by a newer version of the app.
+    TooNew { found: u32, known: u32 },
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::TooNew { found, known } => write!(
+                f,
+                "Database schema version {} is newer than the latest known version {}",
+                found, known
+            ),
+            MigrationError::Sqlite(e) => write!(f, "Migration failed: {}", e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(e: rusqlite::Error) -> Self {
+        MigrationError::Sqlite(e)
+    }
+}
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+pub fn schema_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.query_row("PRAGMA user_version", (), |row| row.get(0))
+}
+
+// Brings the database up to the latest schema and returns its version.
+pub fn migrate(conn: &mut Connection) -> Result<u32, MigrationError> {
+    let current = schema_version(conn)?;
+    let known = latest_version();
+    if current > known {
+        return Err(MigrationError::TooNew {
+            found: current,
+            known,
+        });
+    }
+    if current == known {
+        return Ok(current);
+    }
+    let tx = conn.transaction()?;
+    for migration in &MIGRATIONS[current as usize..] {
+        migration(&tx)?;
+    }
+    tx.pragma_update(None, "user_version", known)?;
+    tx.commit()?;
+    Ok(known)
+}
+
+fn column_exists(tx: &Transaction, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut stmt = tx.prepare(format!("PRAGMA table_info({})", table).as_str())?;
+    let names = stmt
+        .query_map((), |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(names.iter().any(|name| name == column))
+}
+
+// Databases from before migrations existed may already have some of the
+// columns added below, so those migrations only add what is missing.
+fn add_column_if_missing(
+    tx: &Transaction,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    if !column_exists(tx, table, column)? {
+        tx.execute(
+            format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition).as_str(),
+            (),
+        )?;
+    }
+    Ok(())
+}
+
+// 1: The tables as the app originally created them.
+fn baseline(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "create table if not exists recipes (
+             id integer primary key,
+             name text not null unique,
+             description text
+         );
+         create table if not exists ingredients (
+             id integer primary key,
+             name text not null unique
+         );
+         create table if not exists recipe_ingredients (
+             recipe_id integer not null references recipes(id),
+             ingredient_id  integer not null references ingredients(id),
+             primary key (recipe_id, ingredient_id)
+         );",
+    )
+}
+
+// 2: How much of an ingredient a recipe uses.
+fn ingredient_amounts(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "recipe_ingredients", "quantity", "real")?;
+    add_column_if_missing(tx, "recipe_ingredients", "unit", "text")?;
+    add_column_if_missing(tx, "recipe_ingredients", "note", "text")
+}
+
+// 3: How many servings a recipe makes, used for scaling.
+fn recipe_servings(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "recipes", "servings", "integer")
+}
+
+// 4: Full-text index over recipes, filled from the existing ones.
+fn recipe_search_index(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "create virtual table recipes_fts using fts5(
+             name,
+             description,
+             ingredients,
+             tokenize = 'unicode61 remove_diacritics 2'
+         );
+         insert into recipes_fts (rowid, name, description, ingredients)
+         select r.id, r.name, coalesce(r.description, ''),
+             coalesce((select group_concat(i.name, ' ')
+                 from recipe_ingredients as ri
+                 join ingredients as i on i.id = ri.ingredient_id
+                 where ri.recipe_id = r.id), '')
+         from recipes as r;",
+    )
+}
+
+// 5: Ingredients we have at home.
+fn pantry(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "create table pantry (
+             ingredient_id integer primary key references ingredients(id),
+             quantity real,
+             unit text,
+             expires_on text
+         );",
+    )
+}
+
+// 6: Shopping lists generated from recipes, with items to check off.
+fn shopping_lists(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "create table shopping_lists (
+             id integer primary key,
+             name text not null,
+             created_on text not null
+         );
+         create table shopping_list_recipes (
+             list_id integer not null references shopping_lists(id),
+             recipe_id integer not null references recipes(id),
+             primary key (list_id, recipe_id)
+         );
+         create table shopping_list_items (
+             id integer primary key,
+             list_id integer not null references shopping_lists(id),
+             ingredient_id integer not null references ingredients(id),
+             quantity real,
+             unit text,
+             checked integer not null default 0
+         );",
+    )
+}
+
+// 7: Which recipe we cook for each meal of a day.
+fn meal_plan(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "create table meal_plan (
+             day text not null,
+             slot text not null,
+             recipe_id integer not null references recipes(id),
+             primary key (day, slot)
+         );",
+    )
+}
+
+// 8: Preparation, cooking and total time in minutes.
+fn recipe_times(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "recipes", "prep_minutes", "integer")?;
+    add_column_if_missing(tx, "recipes", "cook_minutes", "integer")?;
+    add_column_if_missing(tx, "recipes", "total_minutes", "integer")
+}
+
+// 9: Ordered steps instead of one description. Existing descriptions are
+// split into steps at blank lines; the search index then covers the steps.
+// Only the blank lines and the whitespace around each step are lost.
+fn recipe_steps(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "create table recipe_steps (
+             id integer primary key,
+             recipe_id integer not null references recipes(id),
+             position integer not null,
+             text text not null,
+             timer_seconds integer
+         );
+         create index recipe_steps_by_recipe on recipe_steps (recipe_id, position);
+         create table recipe_step_ingredients (
+             step_id integer not null references recipe_steps(id),
+             ingredient_id integer not null references ingredients(id),
+             primary key (step_id, ingredient_id)
+         );",
+    )?;
+    let descriptions = {
+        let mut stmt = tx.prepare(
+            "select id, description from recipes
+             where description is not null and trim(description) != ''",
+        )?;
+        let rows = stmt
+            .query_map((), |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(i64, String)>>>()?;
+        rows
+    };
+    let blank_line = Regex::new(r"\n[ \t\r]*\n").unwrap();
+    for (id, description) in descriptions {
+        let paragraphs = blank_line
+            .split(&description)
+            .map(str::trim)
+            .filter(|p| !p.is_empty());
+        for (position, text) in paragraphs.enumerate() {
+            tx.execute(
+                "insert into recipe_steps (recipe_id, position, text) values (?1, ?2, ?3)",
+                (id, position as i64, text),
+            )?;
+        }
+        tx.execute("update recipes set description = null where id = ?1", [id])?;
+    }
+    tx.execute_batch(
+        "delete from recipes_fts;
+         insert into recipes_fts (rowid, name, description, ingredients)
+         select r.id, r.name,
+             trim(coalesce(r.description, '') || ' ' ||
+                 coalesce((select group_concat(s.text, ' ')
+                     from recipe_steps as s where s.recipe_id = r.id), '')),
+             coalesce((select group_concat(i.name, ' ')
+                 from recipe_ingredients as ri
+                 join ingredients as i on i.id = ri.ingredient_id
+                 where ri.recipe_id = r.id), '')
+         from recipes as r;",
+    )
+}
+
+// 10: Running cook mode timers, shared by every device.
+fn cook_timers(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "create table cook_timers (
+             id integer primary key,
+             recipe_id integer not null references recipes(id),
+             step integer not null,
+             label text not null,
+             duration_seconds integer not null,
+             started_at integer not null
+         );",
+    )
+}
+
+// 11: Accounts and their login sessions, instead of one user and password
+// on the command line.
+fn users(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "create table users (
+             id integer primary key,
+             name text not null unique,
+             password_hash text not null
+         );
+         create table sessions (
+             token text primary key,
+             user_id integer not null references users(id),
+             expires_at integer not null
+         );
+         create index sessions_user on sessions(user_id);",
+    )
+}
+
+// 12: Owners and visibility of recipes, and groups of users to share
+// them with. Existing recipes have no owner.
+fn recipe_sharing(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "create table user_groups (
+             id integer primary key,
+             name text not null unique
+         );
+         create table group_members (
+             group_id integer not null references user_groups(id),
+             user_id integer not null references users(id),
+             primary key (group_id, user_id)
+         );
+         alter table recipes add column owner_id integer references users(id);
+         alter table recipes add column visibility text not null default 'private';
+         alter table recipes add column group_id integer references user_groups(id);
+         alter table recipes add column public_token text;
+         create unique index recipes_public_token on recipes(public_token);",
+    )
+}
+
+// 13: A CSRF token per session, which forms that change something have to
+// send back. Sessions that are already logged in get one too.
+fn session_csrf_tokens(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "alter table sessions add column csrf_token text;
+         update sessions set csrf_token = lower(hex(randomblob(32)));",
+    )
+}
+
+// 14: Finding the recipes that use an ingredient, for the ingredient search,
+// without looking at every recipe.
+fn recipes_by_ingredient(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "create index recipe_ingredients_by_ingredient
+             on recipe_ingredients (ingredient_id, recipe_id);",
+    )
+}
+
+// 15: Pausing cook mode timers. A paused timer keeps the time it was paused
+// at, and resuming it moves its start on by how long it was paused.
+fn cook_timer_pauses(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "cook_timers", "paused_at", "integer")
+}
+
+// 16: Cook mode timers belong to the user who started them, so people
+// viewing the same recipe don't stop each other's timers. Timers started
+// before this have no owner and are dropped; they last an hour at most.
+fn cook_timer_users(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "cook_timers", "user_id", "integer references users(id)")?;
+    tx.execute("DELETE FROM cook_timers WHERE user_id IS NULL", ())?;
+    Ok(())
+}
+
+// 17: A secret token per user for subscribing to the meal plan from a
+// calendar app, which can't log in. Made when the plan is first shown.
+fn calendar_tokens(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "alter table users add column calendar_token text;
+         create unique index users_calendar_token on users(calendar_token);",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A database as created by the app before migrations existed.
+    fn baseline_fixture() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "create table recipes (
+                 id integer primary key,
+                 name text not null unique,
+                 description text
+             );
+             create table ingredients (
+                 id integer primary key,
+                 name text not null unique
+             );
+             create table recipe_ingredients (
+                 recipe_id integer not null references recipes(id),
+                 ingredient_id  integer not null references ingredients(id),
+                 primary key (recipe_id, ingredient_id)
+             );
+             insert into recipes (id, name, description) values (1, 'Pancakes', 'Mix and fry');
+             insert into ingredients (id, name) values (1, 'flour'), (2, 'milk');
+             insert into recipe_ingredients (recipe_id, ingredient_id) values (1, 1), (1, 2);",
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn upgrades_baseline_database() {
+        let mut conn = baseline_fixture();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+
+        assert_eq!(migrate(&mut conn).unwrap(), latest_version());
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+
+        let (name, servings): (String, Option<u32>) = conn
+            .query_row(
+                "SELECT name, servings FROM recipes WHERE id = 1",
+                (),
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(name, "Pancakes");
+        assert_eq!(servings, None);
+        let linked: u32 = conn
+            .query_row(
+                "SELECT count(*) FROM recipe_ingredients WHERE recipe_id = 1 AND quantity IS NULL",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(linked, 2);
+        let indexed: u32 = conn
+            .query_row(
+                "SELECT rowid FROM recipes_fts WHERE recipes_fts MATCH 'milk'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexed, 1);
+    }
+
+    #[test]
+    fn splits_descriptions_into_steps() {
+        let mut conn = baseline_fixture();
+        conn.execute_batch(
+            "insert into recipes (id, name, description) values
+                 (2, 'Bread', 'Knead.\r\n\r\nLet it rise.\n  \n\nBake.'),
+                 (3, 'Toast', '   ');",
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+        let mut stmt = conn
+            .prepare("SELECT recipe_id, text FROM recipe_steps ORDER BY recipe_id, position")
+            .unwrap();
+        let steps = stmt
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(u32, String)>>>()
+            .unwrap();
+        assert_eq!(
+            steps,
+            vec![
+                (1, "Mix and fry".to_string()),
+                (2, "Knead.".to_string()),
+                (2, "Let it rise.".to_string()),
+                (2, "Bake.".to_string()),
+            ]
+        );
+        let description: Option<String> = conn
+            .query_row("SELECT description FROM recipes WHERE id = 2", (), |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(description, None);
+        let indexed: u32 = conn
+            .query_row(
+                "SELECT rowid FROM recipes_fts WHERE recipes_fts MATCH 'rise'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexed, 2);
+    }
+
+    #[test]
+    fn creates_fresh_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(migrate(&mut conn).unwrap(), latest_version());
+        conn.execute(
+            "INSERT INTO recipe_ingredients (recipe_id, ingredient_id, quantity, unit, note)
+             VALUES (1, 1, 200, 'g', 'sifted')",
+            (),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn upgrades_database_with_columns_added_before_migrations() {
+        let mut conn = baseline_fixture();
+        conn.execute_batch(
+            "alter table recipe_ingredients add column quantity real;
+             alter table recipe_ingredients add column unit text;
+             alter table recipe_ingredients add column note text;",
+        )
+        .unwrap();
+        assert_eq!(migrate(&mut conn).unwrap(), latest_version());
+        assert!(column_exists(&conn.transaction().unwrap(), "recipes", "servings").unwrap());
+    }
+
+    #[test]
+    fn running_twice_is_a_no_op() {
+        let mut conn = baseline_fixture();
+        migrate(&mut conn).unwrap();
+        assert_eq!(migrate(&mut conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn refuses_newer_schema() {
+        let mut conn = baseline_fixture();
+        conn.pragma_update(None, "user_version", latest_version() + 1)
+            .unwrap();
+        match migrate(&mut conn) {
+            Err(MigrationError::TooNew { found, known }) => {
+                assert_eq!(found, latest_version() + 1);
+                assert_eq!(known, latest_version());
+            }
+            other => panic!("Expected TooNew, got {:?}", other),
+        }
+        // Nothing was touched.
+        let tx = conn.transaction().unwrap();
+        assert!(!column_exists(&tx, "recipes", "servings").unwrap());
+    }
+
+    #[test]
+    fn failed_migration_rolls_back() {
+        let mut conn = baseline_fixture();
+        // The baseline migration recreates `ingredients`, then adding
+        // columns to what is now a view fails.
+        conn.execute_batch(
+            "drop table ingredients;
+             drop table recipe_ingredients;
+             create view recipe_ingredients as select 1 as recipe_id, 1 as ingredient_id;",
+        )
+        .unwrap();
+        assert!(matches!(migrate(&mut conn), Err(MigrationError::Sqlite(_))));
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+        let tables: u32 = conn
+            .query_row(
+                "SELECT count(*) FROM sqlite_master WHERE name = 'ingredients'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(tables, 0);
+    }
+}
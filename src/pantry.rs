@@ -0,0 +1,409 @@
+// What we currently have at home. The pantry drives the "what can I cook"
+// search and prefers recipes that use up ingredients expiring soon.
+
+use crate::dates::Date;
+use crate::db;
+use crate::errors::Result;
+use crate::recipe_form::FieldErrors;
+use crate::units::{format_quantity, parse_quantity};
+use crate::users::{self, User};
+use crate::{
+    escape_html, get_con, get_filtered_recipes, id_from_request, ingredient_names_html,
+    ingredients_select_html_by_ing, query_value, resolve_ingredient_lines, return_redirect,
+    serve_html, template::Template, Ingredient, IngredientLine, RecipeIngredient, RecipeResult,
+    SearchFilter,
+};
+use rusqlite::{named_params, Connection};
+use std::collections::HashMap;
+use tiny_http::{Request, ResponseBox};
+
+// Ingredients expiring within this many days are used up first.
+const EXPIRING_SOON_DAYS: i64 = 7;
+
+pub struct PantryItem {
+    pub ingredient: Ingredient,
+    pub quantity: Option<f64>,
+    pub unit: Option<String>,
+    // YYYY-MM-DD.
+    pub expires_on: Option<String>,
+}
+
+impl PantryItem {
+    fn render_row(&self, soon: &str, csrf_field: &str) -> String {
+        let mut amount = self.quantity.map(format_quantity).unwrap_or_default();
+        if let Some(unit) = &self.unit {
+            amount += " ";
+            amount += unit.as_str();
+        }
+        let expires_on = self.expires_on.clone().unwrap_or_default();
+        let class = match !expires_on.is_empty() && expires_on.as_str() <= soon {
+            true => " class=\"expiring\"",
+            false => "",
+        };
+        format!(
+            "<tr{}>
+                <td>{}</td>
+                <td>{}</td>
+                <td>{}</td>
+                <td>
+                    <form action=\"/pantry/remove/{}\" method=\"POST\">
+                        {}
+                        <button type=\"submit\">Remove</button>
+                    </form>
+                </td>
+            </tr>",
+            class,
+            escape_html(&self.ingredient.name),
+            escape_html(&amount),
+            expires_on,
+            self.ingredient.id,
+            csrf_field
+        )
+    }
+}
+
+// Soonest to expire first, items without a date last.
+pub fn get_pantry(con: &Connection) -> rusqlite::Result<Vec<PantryItem>> {
+    let mut stmt = con.prepare(
+        "SELECT i.id, i.name, p.quantity, p.unit, p.expires_on
+         FROM pantry AS p JOIN ingredients AS i ON i.id = p.ingredient_id
+         ORDER BY p.expires_on IS NULL, p.expires_on, i.name",
+    )?;
+    let items = stmt
+        .query_map((), |row| {
+            Ok(PantryItem {
+                ingredient: Ingredient {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                },
+                quantity: row.get(2)?,
+                unit: row.get(3)?,
+                expires_on: row.get(4)?,
+            })
+        })?
+        .collect();
+    items
+}
+
+fn expiring_soon_limit() -> String {
+    Date::today().add_days(EXPIRING_SOON_DAYS).to_string()
+}
+
+pub fn pantry_page(request: &Request) -> Result<ResponseBox> {
+    form_page(request, &HashMap::new(), &FieldErrors::default())
+}
+
+// The pantry with the add form filled in from `form`, and any `errors`
+// next to their fields. A form with errors is sent as a 400.
+fn form_page(
+    request: &Request,
+    form: &HashMap<String, String>,
+    errors: &FieldErrors,
+) -> Result<ResponseBox> {
+    let mut page = Template::page("pantry.html");
+    let soon = expiring_soon_limit();
+    let csrf_field = users::csrf_field(request)?;
+    let items = get_pantry(&*get_con()?)?
+        .iter()
+        .map(|item| item.render_row(&soon, &csrf_field))
+        .collect::<Vec<String>>()
+        .join("");
+    page.set_html("items", items);
+    page.set_html("ingredient_names", ingredient_names_html()?);
+    for field in ["ingredient", "quantity", "unit", "expires_on"] {
+        page.set(field, form.get(field).map(String::as_str).unwrap_or(""));
+    }
+    page.set_html("expires_on_error", errors.html("expires_on"));
+
+    let response = serve_html(request, page)?;
+    match errors.is_empty() {
+        true => Ok(response),
+        false => Ok(response.with_status_code(400)),
+    }
+}
+
+// Adds an ingredient to the pantry, or updates it if it's already there.
+pub fn pantry_page_post(request: &mut Request) -> Result<ResponseBox> {
+    let param_map = users::read_checked_form(request)?;
+    let value = |key: &str| {
+        param_map
+            .get(key)
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    };
+    let Some(name) = value("ingredient") else {
+        return return_redirect("/pantry".to_string());
+    };
+    let Ok(expires_on) = expiry_date(value("expires_on").as_deref()) else {
+        let mut errors = FieldErrors::default();
+        errors.add("expires_on", "Enter the date as YYYY-MM-DD.");
+        let form = ["ingredient", "quantity", "unit", "expires_on"]
+            .into_iter()
+            .filter_map(|field| Some((field.to_string(), param_map.get(field)?.clone())))
+            .collect();
+        return form_page(request, &form, &errors);
+    };
+    let line = IngredientLine {
+        ingredient: name,
+        quantity: value("quantity").and_then(|q| parse_quantity(&q)),
+        unit: value("unit"),
+        note: None,
+    };
+    let mut con = get_con()?;
+    let tx = db::write_transaction(&mut con)?;
+    for item in resolve_ingredient_lines(&tx, vec![line])? {
+        save_item(&tx, &item, expires_on.as_deref())?;
+    }
+    tx.commit()?;
+    return_redirect("/pantry".to_string())
+}
+
+// YYYY-MM-DD, or None for an empty field. Err for anything that isn't a
+// date.
+fn expiry_date(text: Option<&str>) -> std::result::Result<Option<String>, ()> {
+    match text {
+        Some(text) => Date::parse(text).map(|d| Some(d.to_string())).ok_or(()),
+        None => Ok(None),
+    }
+}
+
+// Replaces what we had of `item.ingredient`.
+fn save_item(
+    con: &Connection,
+    item: &RecipeIngredient,
+    expires_on: Option<&str>,
+) -> rusqlite::Result<()> {
+    con.execute(
+        "INSERT INTO pantry (ingredient_id, quantity, unit, expires_on)
+         VALUES (:ingredient_id, :quantity, :unit, :expires_on)
+         ON CONFLICT (ingredient_id) DO UPDATE SET
+            quantity = excluded.quantity,
+            unit = excluded.unit,
+            expires_on = excluded.expires_on",
+        named_params! {
+            ":ingredient_id": item.ingredient.id,
+            ":quantity": item.quantity,
+            ":unit": item.unit,
+            ":expires_on": expires_on,
+        },
+    )?;
+    Ok(())
+}
+
+pub fn pantry_remove_post(request: &mut Request) -> Result<ResponseBox> {
+    users::read_checked_form(request)?;
+    if let Some(id) = id_from_request(request) {
+        remove_item(&*get_con()?, id)?;
+    }
+    return_redirect("/pantry".to_string())
+}
+
+fn remove_item(con: &Connection, ingredient_id: usize) -> rusqlite::Result<()> {
+    con.execute(
+        "DELETE FROM pantry WHERE ingredient_id = :id",
+        named_params! { ":id": ingredient_id },
+    )?;
+    Ok(())
+}
+
+// The earliest soon-to-expire pantry ingredient each recipe uses, keyed
+// by recipe id.
+fn expiring_ingredients() -> rusqlite::Result<HashMap<usize, (String, String)>> {
+    let con = get_con()?;
+    let mut stmt = con.prepare(
+        "SELECT ri.recipe_id, i.name, p.expires_on
+         FROM recipe_ingredients AS ri
+         JOIN pantry AS p ON p.ingredient_id = ri.ingredient_id
+         JOIN ingredients AS i ON i.id = ri.ingredient_id
+         WHERE p.expires_on IS NOT NULL AND p.expires_on <= :limit
+         ORDER BY p.expires_on",
+    )?;
+    let rows = stmt.query_map(named_params! { ":limit": expiring_soon_limit() }, |row| {
+        Ok((row.get::<_, usize>(0)?, row.get(1)?, row.get(2)?))
+    })?;
+    let mut expiring = HashMap::new();
+    for row in rows {
+        let (recipe_id, name, expires_on) = row?;
+        expiring.entry(recipe_id).or_insert((name, expires_on));
+    }
+    Ok(expiring)
+}
+
+struct PantryResult {
+    result: RecipeResult,
+    // Ingredient name and date.
+    expiring: Option<(String, String)>,
+}
+
+impl PantryResult {
+    fn render_link(self) -> String {
+        let mut link = self.result.render_link();
+        if let Some((name, expires_on)) = self.expiring {
+            let note = format!(
+                " <span class=\"expiring\">uses {} (expires {})</span>",
+                escape_html(&name),
+                expires_on
+            );
+            link = link.replace("</div>", note.as_str());
+            link += "</div>";
+        }
+        link
+    }
+}
+
+// Recipes we can cook from the pantry. Ones that use up something expiring
+// soon come first, soonest first; the rest are ranked by match.
+pub fn pantry_search_page(request: &Request, user: &User) -> Result<ResponseBox> {
+    let pantry_ids = get_pantry(&*get_con()?)?
+        .iter()
+        .map(|item| item.ingredient.id.to_string())
+        .collect::<Vec<String>>();
+    let max_missing = query_value(request.url(), "max_missing").and_then(|m| m.parse().ok());
+    let mut recipe_html = String::new();
+    if !pantry_ids.is_empty() {
+        let filter = SearchFilter {
+            ingredients: pantry_ids.clone(),
+            max_missing,
+            ..SearchFilter::default()
+        };
+        let mut expiring = expiring_ingredients()?;
+        let mut results = get_filtered_recipes(&*get_con()?, &filter, user)?
+            .into_iter()
+            .map(|result| PantryResult {
+                expiring: expiring.remove(&result.recipe.id),
+                result,
+            })
+            .collect::<Vec<PantryResult>>();
+        // Stable, so recipes keep their match order within the same date.
+        results.sort_by(|a, b| match (&a.expiring, &b.expiring) {
+            (Some((_, a)), Some((_, b))) => a.cmp(b),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        });
+        for result in results {
+            recipe_html += result.render_link().as_str();
+        }
+    }
+
+    let mut page = Template::page("search.html");
+    let all_ingredients = ingredients_select_html_by_ing(None)?;
+    page.set_html(
+        "ingredients",
+        ingredients_select_html_by_ing(Some(pantry_ids))?,
+    );
+    page.set_html("required", all_ingredients.as_str());
+    page.set_html("excluded", all_ingredients);
+    let max_missing = max_missing.map(|m: usize| m.to_string());
+    page.set("max_missing", max_missing.unwrap_or_default());
+    page.set("q", "");
+    page.set_html("recipes", recipe_html);
+    serve_html(request, page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations;
+
+    fn setup() -> Connection {
+        let mut con = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut con).unwrap();
+        for name in ["flour", "milk", "eggs"] {
+            con.execute(
+                "INSERT INTO ingredients (name) VALUES (:name)",
+                named_params! { ":name": name },
+            )
+            .unwrap();
+        }
+        con
+    }
+
+    fn item(con: &Connection, name: &str, quantity: f64, unit: &str) -> RecipeIngredient {
+        let id = con
+            .query_row(
+                "SELECT id FROM ingredients WHERE name = :name",
+                named_params! { ":name": name },
+                |row| row.get(0),
+            )
+            .unwrap();
+        RecipeIngredient {
+            ingredient: Ingredient {
+                id,
+                name: name.to_string(),
+            },
+            quantity: Some(quantity),
+            unit: Some(unit.to_string()),
+            note: None,
+        }
+    }
+
+    // (name, amount, expires_on) of everything, in listing order.
+    fn contents(con: &Connection) -> Vec<(String, String, Option<String>)> {
+        get_pantry(con)
+            .unwrap()
+            .into_iter()
+            .map(|i| {
+                let amount = format!(
+                    "{} {}",
+                    i.quantity.map(format_quantity).unwrap_or_default(),
+                    i.unit.unwrap_or_default()
+                );
+                (i.ingredient.name, amount, i.expires_on)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replaces_what_we_had() {
+        let con = setup();
+        save_item(&con, &item(&con, "milk", 1.0, "l"), Some("2030-01-05")).unwrap();
+        save_item(&con, &item(&con, "milk", 500.0, "ml"), None).unwrap();
+        assert_eq!(
+            contents(&con),
+            vec![("milk".to_string(), "500 ml".to_string(), None)]
+        );
+    }
+
+    #[test]
+    fn removes_items() {
+        let con = setup();
+        let flour = item(&con, "flour", 1.0, "kg");
+        save_item(&con, &flour, None).unwrap();
+        save_item(&con, &item(&con, "eggs", 6.0, ""), None).unwrap();
+        remove_item(&con, flour.ingredient.id).unwrap();
+        let names = contents(&con).into_iter().map(|i| i.0).collect::<Vec<_>>();
+        assert_eq!(names, vec!["eggs"]);
+    }
+
+    #[test]
+    fn lists_soonest_to_expire_first() {
+        let con = setup();
+        save_item(&con, &item(&con, "flour", 1.0, "kg"), None).unwrap();
+        save_item(&con, &item(&con, "milk", 1.0, "l"), Some("2030-02-01")).unwrap();
+        save_item(&con, &item(&con, "eggs", 6.0, ""), Some("2030-01-15")).unwrap();
+        let listed = contents(&con)
+            .into_iter()
+            .map(|i| (i.0, i.2))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listed,
+            vec![
+                ("eggs".to_string(), Some("2030-01-15".to_string())),
+                ("milk".to_string(), Some("2030-02-01".to_string())),
+                ("flour".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_dates_it_cant_read() {
+        assert_eq!(expiry_date(None), Ok(None));
+        assert_eq!(
+            expiry_date(Some("2030-01-05")),
+            Ok(Some("2030-01-05".to_string()))
+        );
+        assert_eq!(expiry_date(Some("next tuesday")), Err(()));
+        assert_eq!(expiry_date(Some("2030-13-01")), Err(()));
+    }
+}
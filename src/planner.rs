@@ -0,0 +1,520 @@
+// The meal plan: which recipe we cook for each meal of a day. Shown and
+// edited a week at a time, and exported as iCalendar so it shows up in
+// calendar apps. Calendar apps can't log in, so each user also gets a
+// secret feed link, /plan/{token}.ics, that works without a session. A new
+// link can be made at any time, which stops the old one working.
+
+use crate::dates::Date;
+use crate::db;
+use crate::errors::Result;
+use crate::users::{self, User};
+use crate::{
+    escape_html, get_con, get_recipes, get_usize, not_found, query_value, return_redirect,
+    serve_bytes, serve_html, sharing, template::Template, RecipeShort,
+};
+use rusqlite::{named_params, Connection, OptionalExtension};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tiny_http::{Method, Request, ResponseBox};
+
+const DAY_NAMES: [&str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Slot {
+    Breakfast,
+    Lunch,
+    Dinner,
+}
+
+impl Slot {
+    const ALL: [Slot; 3] = [Slot::Breakfast, Slot::Lunch, Slot::Dinner];
+
+    fn parse(text: &str) -> Option<Slot> {
+        Slot::ALL.into_iter().find(|slot| slot.as_str() == text)
+    }
+
+    // How the slot is stored.
+    fn as_str(&self) -> &'static str {
+        match self {
+            Slot::Breakfast => "breakfast",
+            Slot::Lunch => "lunch",
+            Slot::Dinner => "dinner",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Slot::Breakfast => "Breakfast",
+            Slot::Lunch => "Lunch",
+            Slot::Dinner => "Dinner",
+        }
+    }
+
+    // Local start time of the calendar event, as HHMMSS.
+    fn start_time(&self) -> &'static str {
+        match self {
+            Slot::Breakfast => "080000",
+            Slot::Lunch => "123000",
+            Slot::Dinner => "183000",
+        }
+    }
+}
+
+// What a meal with a recipe the user can't see is called instead.
+const HIDDEN_RECIPE: &str = "Someone else's recipe";
+
+struct PlannedMeal {
+    day: Date,
+    slot: Slot,
+    recipe: RecipeShort,
+    // Whether the user can see the recipe. If not, its name is
+    // `HIDDEN_RECIPE`, and only the id is kept so saving the week keeps it.
+    visible: bool,
+}
+
+// Meals from `from` up to but not including `until`, or all of them, as
+// `user` may see them.
+fn get_meals(
+    con: &Connection,
+    user: &User,
+    from: Option<Date>,
+    until: Option<Date>,
+) -> rusqlite::Result<Vec<PlannedMeal>> {
+    let mut stmt = con.prepare(&format!(
+        "SELECT m.day, m.slot, r.id, CASE WHEN {} THEN r.name END
+         FROM meal_plan AS m JOIN recipes AS r ON r.id = m.recipe_id
+         WHERE (:from IS NULL OR m.day >= :from)
+            AND (:until IS NULL OR m.day < :until)
+         ORDER BY m.day",
+        sharing::VISIBLE
+    ))?;
+    let rows = stmt
+        .query_map(
+            named_params! {
+                ":from": from.map(|d| d.to_string()),
+                ":until": until.map(|d| d.to_string()),
+                ":user_id": user.id,
+            },
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, usize>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            },
+        )?
+        .collect::<rusqlite::Result<Vec<(String, String, usize, Option<String>)>>>()?;
+    let meals = rows
+        .into_iter()
+        .filter_map(|(day, slot, id, name)| {
+            Some(PlannedMeal {
+                day: Date::parse(&day)?,
+                slot: Slot::parse(&slot)?,
+                visible: name.is_some(),
+                recipe: RecipeShort {
+                    id,
+                    name: name.unwrap_or_else(|| HIDDEN_RECIPE.to_string()),
+                },
+            })
+        })
+        .collect();
+    Ok(meals)
+}
+
+// The week containing `?week=`, or this week.
+fn requested_week(request: &Request) -> Date {
+    query_value(request.url(), "week")
+        .and_then(|w| Date::parse(&w))
+        .unwrap_or_else(Date::today)
+        .week_start()
+}
+
+// Entry point for everything under /plan.
+pub fn handle_planner(request: &mut Request, user: &User) -> Result<ResponseBox> {
+    let url = request.url().to_string();
+    let path = url.split('?').next().unwrap_or_default();
+    match (request.method(), path) {
+        (Method::Get, "/plan") => plan_page(request, user),
+        (Method::Post, "/plan") => plan_page_post(request, user),
+        (Method::Get, "/plan.ics") => serve_calendar(user),
+        (Method::Post, "/plan/calendar-link") => {
+            users::read_checked_form(request)?;
+            new_calendar_token(&*get_con()?, user)?;
+            return_redirect("/plan".to_string())
+        }
+        _ => return_redirect("/plan".to_string()),
+    }
+}
+
+// The feed at the user's secret link, for calendar apps.
+pub fn calendar_feed(request: &Request) -> Result<ResponseBox> {
+    let path = request.url().split('?').next().unwrap_or_default();
+    let user = match path
+        .strip_prefix("/plan/")
+        .and_then(|p| p.strip_suffix(".ics"))
+    {
+        Some(token) => user_by_calendar_token(&*get_con()?, token)?,
+        None => None,
+    };
+    match user {
+        Some(user) => serve_calendar(&user),
+        None => Err(not_found("There's no calendar at this link.")),
+    }
+}
+
+fn serve_calendar(user: &User) -> Result<ResponseBox> {
+    let meals = get_meals(&*get_con()?, user, None, None)?;
+    let calendar = to_icalendar(&meals, SystemTime::now());
+    serve_bytes(calendar.as_bytes(), "text/calendar; charset=utf-8")
+}
+
+// The user's calendar token, made on first use.
+fn calendar_token(con: &Connection, user: &User) -> rusqlite::Result<String> {
+    let token = con.query_row(
+        "SELECT calendar_token FROM users WHERE id = :id",
+        named_params! { ":id": user.id },
+        |row| row.get::<_, Option<String>>(0),
+    )?;
+    match token {
+        Some(token) => Ok(token),
+        None => new_calendar_token(con, user),
+    }
+}
+
+fn new_calendar_token(con: &Connection, user: &User) -> rusqlite::Result<String> {
+    let token = users::new_token();
+    con.execute(
+        "UPDATE users SET calendar_token = :token WHERE id = :id",
+        named_params! { ":token": token, ":id": user.id },
+    )?;
+    Ok(token)
+}
+
+fn user_by_calendar_token(con: &Connection, token: &str) -> rusqlite::Result<Option<User>> {
+    con.query_row(
+        "SELECT id, name FROM users WHERE calendar_token = :token",
+        named_params! { ":token": token },
+        |row| {
+            Ok(User {
+                id: row.get(0)?,
+                name: row.get(1)?,
+            })
+        },
+    )
+    .optional()
+}
+
+// The recipes the user can see, and whatever is planned already, so that
+// saving the week keeps meals someone else planned with their recipes.
+fn recipe_options_html(recipes: &[RecipeShort], planned: Option<&RecipeShort>) -> String {
+    let selected = planned.map(|recipe| recipe.id);
+    let mut options = "<option value=\"\">—</option>".to_string();
+    let hidden = planned.filter(|planned| recipes.iter().all(|r| r.id != planned.id));
+    for recipe in hidden.into_iter().chain(recipes) {
+        let attribute = match Some(recipe.id) == selected {
+            true => " selected",
+            false => "",
+        };
+        options += format!(
+            "<option value=\"{}\"{}>{}</option>",
+            recipe.id,
+            attribute,
+            escape_html(&recipe.name)
+        )
+        .as_str();
+    }
+    options
+}
+
+fn plan_page(request: &Request, user: &User) -> Result<ResponseBox> {
+    let week = requested_week(request);
+    let con = get_con()?;
+    let meals = get_meals(&con, user, Some(week), Some(week.add_days(7)))?;
+    let mut recipes = get_recipes(user)?;
+    recipes.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut page = Template::page("plan.html");
+    page.set(
+        "calendar_link",
+        format!("/plan/{}.ics", calendar_token(&con, user)?),
+    );
+    page.set("week", week);
+    page.set("previous", week.add_days(-7));
+    page.set("next", week.add_days(7));
+    page.set_html("rows", week_rows_html(week, &meals, &recipes));
+    serve_html(request, page)
+}
+
+// A row per day of the week starting at `week`, with a choice of recipe
+// for every meal.
+fn week_rows_html(week: Date, meals: &[PlannedMeal], recipes: &[RecipeShort]) -> String {
+    let mut rows = String::new();
+    for (offset, day_name) in DAY_NAMES.iter().enumerate() {
+        let day = week.add_days(offset as i64);
+        let class = match day == Date::today() {
+            true => " class=\"today\"",
+            false => "",
+        };
+        rows += format!("<tr{}><th>{}<br />{}</th>", class, day_name, day).as_str();
+        for slot in Slot::ALL {
+            let planned = meals.iter().find(|m| m.day == day && m.slot == slot);
+            let link = match planned {
+                Some(m) if m.visible => format!(
+                    "<a href=\"/recipe/{}\">{}</a>",
+                    m.recipe.id,
+                    escape_html(&m.recipe.name)
+                ),
+                Some(m) => escape_html(&m.recipe.name),
+                None => String::new(),
+            };
+            rows += format!(
+                "<td><div>{}</div><select name=\"{}_{}\" aria-label=\"{} {}\">{}</select></td>",
+                link,
+                day,
+                slot.as_str(),
+                day_name,
+                slot.label(),
+                recipe_options_html(recipes, planned.map(|m| &m.recipe))
+            )
+            .as_str();
+        }
+        rows += "</tr>";
+    }
+    rows
+}
+
+// Saves a whole week. Fields are named `YYYY-MM-DD_slot`; an empty value
+// clears the meal. Only recipes the user can see are planned, unless the
+// meal stays as it was.
+fn plan_page_post(request: &mut Request, user: &User) -> Result<ResponseBox> {
+    let param_map = users::read_checked_form(request)?;
+    let mut con = get_con()?;
+    let tx = db::write_transaction(&mut con)?;
+    for (key, value) in param_map.iter() {
+        let Some((day, slot)) = key.split_once('_') else {
+            continue;
+        };
+        let (Some(day), Some(slot)) = (Date::parse(day), Slot::parse(slot)) else {
+            continue;
+        };
+        let unchanged = tx
+            .query_row(
+                "SELECT 1 FROM meal_plan WHERE day = :day AND slot = :slot AND recipe_id = :recipe_id",
+                named_params! {
+                    ":day": day.to_string(),
+                    ":slot": slot.as_str(),
+                    ":recipe_id": get_usize(value),
+                },
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if unchanged {
+            continue;
+        }
+        tx.execute(
+            "DELETE FROM meal_plan WHERE day = :day AND slot = :slot",
+            named_params! { ":day": day.to_string(), ":slot": slot.as_str() },
+        )?;
+        if let Some(recipe_id) = get_usize(value) {
+            tx.execute(
+                &format!(
+                    "INSERT INTO meal_plan (day, slot, recipe_id)
+                     SELECT :day, :slot, r.id FROM recipes AS r
+                     WHERE r.id = :recipe_id AND {}",
+                    sharing::VISIBLE
+                ),
+                named_params! {
+                    ":day": day.to_string(),
+                    ":slot": slot.as_str(),
+                    ":recipe_id": recipe_id,
+                    ":user_id": user.id,
+                },
+            )?;
+        }
+    }
+    tx.commit()?;
+    let week = param_map
+        .get("week")
+        .and_then(|w| Date::parse(w))
+        .unwrap_or_else(Date::today);
+    return_redirect(format!("/plan?week={}", week))
+}
+
+// TEXT values per RFC 5545 section 3.3.11.
+fn escape_ics(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+// Lines longer than 75 bytes are continued on the next line after a space,
+// without splitting a UTF-8 character.
+fn fold_ics_line(line: &str) -> String {
+    let mut folded = String::new();
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            folded += "\r\n ";
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded + "\r\n"
+}
+
+fn ics_timestamp(now: SystemTime) -> String {
+    let seconds = now
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let day = Date::from_days((seconds / 86_400) as i64);
+    let seconds = seconds % 86_400;
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        day.year,
+        day.month,
+        day.day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// One hour long events in floating local time, so dinner is at 18:30
+// wherever the calendar is.
+fn to_icalendar(meals: &[PlannedMeal], now: SystemTime) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//recipe-helper//meal plan//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "X-WR-CALNAME:Meal plan".to_string(),
+    ];
+    let stamp = ics_timestamp(now);
+    for meal in meals {
+        let day = meal.day.to_string().replace('-', "");
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:{}-{}@recipe-helper", day, meal.slot.as_str()));
+        lines.push(format!("DTSTAMP:{}", stamp));
+        lines.push(format!("DTSTART:{}T{}", day, meal.slot.start_time()));
+        lines.push("DURATION:PT1H".to_string());
+        lines.push(format!(
+            "SUMMARY:{}",
+            escape_ics(&format!("{}: {}", meal.slot.label(), meal.recipe.name))
+        ));
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+    lines.iter().map(|line| fold_ics_line(line)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations;
+    use std::time::Duration;
+
+    #[test]
+    fn exports_icalendar() {
+        let meals = vec![PlannedMeal {
+            day: Date::parse("2024-03-04").unwrap(),
+            slot: Slot::Dinner,
+            recipe: RecipeShort {
+                id: 1,
+                name: "Fish, chips; peas".to_string(),
+            },
+            visible: true,
+        }];
+        let now = UNIX_EPOCH + Duration::from_secs(1_709_600_000);
+        let calendar = to_icalendar(&meals, now);
+        assert!(calendar.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+        assert!(calendar.contains(
+            "BEGIN:VEVENT\r\n\
+             UID:20240304-dinner@recipe-helper\r\n\
+             DTSTAMP:20240305T005320Z\r\n\
+             DTSTART:20240304T183000\r\n\
+             DURATION:PT1H\r\n\
+             SUMMARY:Dinner: Fish\\, chips\\; peas\r\n\
+             END:VEVENT\r\n"
+        ));
+        assert!(calendar.ends_with("END:VCALENDAR\r\n"));
+    }
+
+    #[test]
+    fn folds_long_lines() {
+        let line = format!("SUMMARY:{}", "é".repeat(40));
+        let folded = fold_ics_line(&line);
+        let parts = folded.trim_end().split("\r\n").collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 2);
+        assert!(parts.iter().all(|part| part.len() <= 75));
+        assert_eq!(parts.concat().replacen(" é", "é", 1), line);
+    }
+
+    // Ada (1) planned her private soup and Bob's (2) public cake.
+    #[test]
+    fn hides_private_recipes_of_others() {
+        let mut con = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut con).unwrap();
+        con.execute_batch(
+            "insert into users (id, name, password_hash) values (1, 'ada', ''), (2, 'bob', '');
+             insert into recipes (id, name, owner_id, visibility) values
+                 (1, 'Secret soup', 1, 'private'), (2, 'Cake', 2, 'public');
+             insert into meal_plan (day, slot, recipe_id) values
+                 ('2024-03-04', 'lunch', 1), ('2024-03-04', 'dinner', 2);",
+        )
+        .unwrap();
+        let week = Date::parse("2024-03-04").unwrap();
+        let bob = User {
+            id: 2,
+            name: "bob".to_string(),
+        };
+        let meals = get_meals(&con, &bob, Some(week), Some(week.add_days(7))).unwrap();
+        let rows = week_rows_html(week, &meals, &[]);
+        assert!(!rows.contains("Secret soup"));
+        assert!(rows.contains(HIDDEN_RECIPE.replace('\'', "&#39;").as_str()));
+        // Still planned when Bob saves the week.
+        assert!(rows.contains("<option value=\"1\" selected>"));
+        assert!(rows.contains("<a href=\"/recipe/2\">Cake</a>"));
+        let calendar = to_icalendar(&meals, SystemTime::now());
+        assert!(!calendar.contains("Secret soup"));
+
+        let ada = User {
+            id: 1,
+            name: "ada".to_string(),
+        };
+        let meals = get_meals(&con, &ada, None, None).unwrap();
+        assert!(meals.iter().all(|meal| meal.visible));
+    }
+
+    #[test]
+    fn finds_users_by_calendar_token() {
+        let mut con = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut con).unwrap();
+        con.execute_batch("insert into users (id, name, password_hash) values (1, 'ada', '');")
+            .unwrap();
+        let ada = User {
+            id: 1,
+            name: "ada".to_string(),
+        };
+        let token = calendar_token(&con, &ada).unwrap();
+        assert_eq!(calendar_token(&con, &ada).unwrap(), token);
+        assert_eq!(user_by_calendar_token(&con, &token).unwrap(), Some(ada));
+
+        let ada = user_by_calendar_token(&con, &token).unwrap().unwrap();
+        let renewed = new_calendar_token(&con, &ada).unwrap();
+        assert_eq!(user_by_calendar_token(&con, &token).unwrap(), None);
+        assert_eq!(user_by_calendar_token(&con, &renewed).unwrap(), Some(ada));
+        assert_eq!(user_by_calendar_token(&con, "").unwrap(), None);
+    }
+}
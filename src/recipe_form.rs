@@ -0,0 +1,519 @@
+// The add and edit form. What was entered is kept as text, so a form with
+// mistakes can be shown again exactly as it was, with a message next to
+// each field that needs fixing. Nothing is saved, and no new ingredients
+// are created, until the whole form is valid.
+
+use crate::forms::Form;
+use crate::units::{format_quantity, parse_quantity};
+use crate::{escape_html, IngredientLine, Recipe, StepLine};
+
+// In characters.
+const NAME_MAX: usize = 200;
+const DESCRIPTION_MAX: usize = 20_000;
+const STEP_MAX: usize = 5_000;
+const INGREDIENT_MAX: usize = 100;
+const UNIT_MAX: usize = 30;
+const NOTE_MAX: usize = 200;
+// Ingredient lines and steps each.
+const ROWS_MAX: usize = 200;
+const SERVINGS_MAX: u32 = 1000;
+// A week, for the times and for timers.
+const MINUTES_MAX: u32 = 7 * 24 * 60;
+
+// The fields that have their own `{FIELD_error}` on the form.
+pub const FIELDS: [&str; 6] = [
+    "name",
+    "servings",
+    "minutes",
+    "ingredients",
+    "steps",
+    "description",
+];
+
+#[derive(Default, Debug, PartialEq)]
+pub struct IngredientRow {
+    pub quantity: String,
+    pub unit: String,
+    pub ingredient: String,
+    pub note: String,
+}
+
+#[derive(Default, Debug, PartialEq)]
+pub struct StepRow {
+    pub text: String,
+    pub timer_minutes: String,
+    // Comma separated names.
+    pub ingredients: String,
+}
+
+#[derive(Default, Debug)]
+pub struct RecipeForm {
+    pub name: String,
+    pub servings: String,
+    pub prep_minutes: String,
+    pub cook_minutes: String,
+    pub total_minutes: String,
+    pub description: String,
+    pub ingredients: Vec<IngredientRow>,
+    pub steps: Vec<StepRow>,
+}
+
+// Messages by field, in the order they were found.
+#[derive(Default, Debug)]
+pub struct FieldErrors(Vec<(&'static str, String)>);
+
+impl FieldErrors {
+    pub fn add(&mut self, field: &'static str, message: impl Into<String>) {
+        self.0.push((field, message.into()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn messages(&self, field: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(f, _)| *f == field)
+            .map(|(_, message)| message.as_str())
+            .collect()
+    }
+
+    // What goes in `{FIELD_error}`.
+    pub fn html(&self, field: &str) -> String {
+        self.messages(field)
+            .iter()
+            .map(|message| format!("<div class=\"form-error\">{}</div>", escape_html(message)))
+            .collect()
+    }
+}
+
+// A whole number in `min..=max`, or None for an empty field. Err for
+// anything else.
+fn whole_number(text: &str, min: u32, max: u32) -> Result<Option<u32>, ()> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    match text.parse::<u32>() {
+        Ok(number) if (min..=max).contains(&number) => Ok(Some(number)),
+        _ => Err(()),
+    }
+}
+
+fn optional(text: &str) -> Option<String> {
+    Some(text.trim().to_string()).filter(|t| !t.is_empty())
+}
+
+fn too_long(text: &str, max: usize) -> bool {
+    text.chars().count() > max
+}
+
+impl IngredientRow {
+    fn is_empty(&self) -> bool {
+        [&self.quantity, &self.unit, &self.ingredient, &self.note]
+            .iter()
+            .all(|v| v.trim().is_empty())
+    }
+}
+
+impl StepRow {
+    fn is_empty(&self) -> bool {
+        [&self.text, &self.timer_minutes, &self.ingredients]
+            .iter()
+            .all(|v| v.trim().is_empty())
+    }
+
+    fn ingredient_names(&self) -> Vec<String> {
+        self.ingredients
+            .split(',')
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+}
+
+impl RecipeForm {
+    // Rows that were left blank are dropped.
+    pub fn from_form(form: &Form) -> RecipeForm {
+        let value = |key: &str| form.get(key).cloned().unwrap_or_default();
+        let column = |key: &str| -> Vec<String> {
+            form.get_all(key).into_iter().map(String::from).collect()
+        };
+        let cell = |values: &[String], index: usize| values.get(index).cloned().unwrap_or_default();
+        let (names, quantities, units, notes) = (
+            column("ingredient"),
+            column("quantity"),
+            column("unit"),
+            column("note"),
+        );
+        let rows = [&names, &quantities, &units, &notes]
+            .iter()
+            .map(|values| values.len())
+            .max()
+            .unwrap_or(0);
+        let ingredients = (0..rows)
+            .map(|index| IngredientRow {
+                quantity: cell(&quantities, index),
+                unit: cell(&units, index),
+                ingredient: cell(&names, index),
+                note: cell(&notes, index),
+            })
+            .filter(|row| !row.is_empty())
+            .collect();
+        let (texts, timers, step_ingredients) = (
+            column("step_text"),
+            column("step_timer"),
+            column("step_ingredients"),
+        );
+        let rows = texts.len().max(timers.len()).max(step_ingredients.len());
+        let steps = (0..rows)
+            .map(|index| StepRow {
+                text: cell(&texts, index),
+                timer_minutes: cell(&timers, index),
+                ingredients: cell(&step_ingredients, index),
+            })
+            .filter(|row| !row.is_empty())
+            .collect();
+        RecipeForm {
+            name: value("name"),
+            servings: value("servings"),
+            prep_minutes: value("prep_minutes"),
+            cook_minutes: value("cook_minutes"),
+            total_minutes: value("total_minutes"),
+            description: value("description"),
+            ingredients,
+            steps,
+        }
+    }
+
+    pub fn from_recipe(recipe: &Recipe) -> RecipeForm {
+        let number = |n: Option<u32>| n.map(|n| n.to_string()).unwrap_or_default();
+        RecipeForm {
+            name: recipe.name.clone(),
+            servings: number(recipe.servings),
+            prep_minutes: number(recipe.prep_minutes),
+            cook_minutes: number(recipe.cook_minutes),
+            total_minutes: number(recipe.total_minutes),
+            description: recipe.description.clone().unwrap_or_default(),
+            ingredients: recipe
+                .ingredients
+                .iter()
+                .map(|i| IngredientRow {
+                    quantity: i.quantity.map(format_quantity).unwrap_or_default(),
+                    unit: i.unit.clone().unwrap_or_default(),
+                    ingredient: i.ingredient.name.clone(),
+                    note: i.note.clone().unwrap_or_default(),
+                })
+                .collect(),
+            steps: recipe
+                .steps
+                .iter()
+                .map(|step| StepRow {
+                    text: step.text.clone(),
+                    timer_minutes: step
+                        .timer_seconds
+                        .map(|s| format_quantity(s as f64 / 60.0))
+                        .unwrap_or_default(),
+                    ingredients: step
+                        .ingredients
+                        .iter()
+                        .map(|i| i.name.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(", "),
+                })
+                .collect(),
+        }
+    }
+
+    // Everything but the duplicate name check, which needs the database.
+    pub fn validate(&self) -> FieldErrors {
+        let mut errors = FieldErrors::default();
+
+        let name = self.name.trim();
+        if name.is_empty() {
+            errors.add("name", "Give the recipe a name.");
+        } else if too_long(name, NAME_MAX) {
+            errors.add(
+                "name",
+                format!("The name can be at most {} characters long.", NAME_MAX),
+            );
+        }
+
+        if whole_number(&self.servings, 1, SERVINGS_MAX).is_err() {
+            errors.add(
+                "servings",
+                format!(
+                    "Servings has to be a whole number from 1 to {}.",
+                    SERVINGS_MAX
+                ),
+            );
+        }
+        for (label, value) in [
+            ("Prep", &self.prep_minutes),
+            ("Cook", &self.cook_minutes),
+            ("Total", &self.total_minutes),
+        ] {
+            if whole_number(value, 0, MINUTES_MAX).is_err() {
+                errors.add(
+                    "minutes",
+                    format!(
+                        "{} minutes has to be a whole number from 0 to {}.",
+                        label, MINUTES_MAX
+                    ),
+                );
+            }
+        }
+
+        if too_long(&self.description, DESCRIPTION_MAX) {
+            errors.add(
+                "description",
+                format!(
+                    "The description can be at most {} characters long.",
+                    DESCRIPTION_MAX
+                ),
+            );
+        }
+
+        self.validate_ingredients(&mut errors);
+        self.validate_steps(&mut errors);
+        errors
+    }
+
+    fn validate_ingredients(&self, errors: &mut FieldErrors) {
+        if self.ingredients.len() > ROWS_MAX {
+            errors.add(
+                "ingredients",
+                format!("A recipe can have at most {} ingredients.", ROWS_MAX),
+            );
+        }
+        if self
+            .ingredients
+            .iter()
+            .all(|row| row.ingredient.trim().is_empty())
+        {
+            errors.add("ingredients", "Add at least one ingredient.");
+        }
+        for (index, row) in self.ingredients.iter().enumerate() {
+            let line = index + 1;
+            let ingredient = row.ingredient.trim();
+            if ingredient.is_empty() {
+                errors.add(
+                    "ingredients",
+                    format!("Line {} has an amount or note but no ingredient.", line),
+                );
+            } else if too_long(ingredient, INGREDIENT_MAX) {
+                errors.add(
+                    "ingredients",
+                    format!(
+                        "Line {}: an ingredient can be at most {} characters long.",
+                        line, INGREDIENT_MAX
+                    ),
+                );
+            }
+            let quantity = row.quantity.trim();
+            if !quantity.is_empty() && !parse_quantity(quantity).is_some_and(|q| q >= 0.0) {
+                errors.add(
+                    "ingredients",
+                    format!(
+                        "Line {}: \"{}\" isn't an amount, use e.g. 2, 0.5 or 1 1/2.",
+                        line, quantity
+                    ),
+                );
+            }
+            if too_long(row.unit.trim(), UNIT_MAX) || too_long(row.note.trim(), NOTE_MAX) {
+                errors.add(
+                    "ingredients",
+                    format!(
+                        "Line {}: the unit can be at most {} and the note at most {} characters long.",
+                        line, UNIT_MAX, NOTE_MAX
+                    ),
+                );
+            }
+        }
+    }
+
+    fn validate_steps(&self, errors: &mut FieldErrors) {
+        if self.steps.len() > ROWS_MAX {
+            errors.add(
+                "steps",
+                format!("A recipe can have at most {} steps.", ROWS_MAX),
+            );
+        }
+        let ingredients = self
+            .ingredients
+            .iter()
+            .map(|row| row.ingredient.trim().to_lowercase())
+            .collect::<Vec<String>>();
+        for (index, row) in self.steps.iter().enumerate() {
+            let step = index + 1;
+            let text = row.text.trim();
+            if text.is_empty() {
+                errors.add("steps", format!("Step {} has no text.", step));
+            } else if too_long(text, STEP_MAX) {
+                errors.add(
+                    "steps",
+                    format!("Step {} can be at most {} characters long.", step, STEP_MAX),
+                );
+            }
+            let timer = row.timer_minutes.trim();
+            if !timer.is_empty()
+                && !parse_quantity(timer).is_some_and(|m| m > 0.0 && m <= MINUTES_MAX as f64)
+            {
+                errors.add(
+                    "steps",
+                    format!(
+                        "Step {}: the timer has to be a number of minutes up to {}.",
+                        step, MINUTES_MAX
+                    ),
+                );
+            }
+            for name in row.ingredient_names() {
+                if !ingredients.contains(&name.to_lowercase()) {
+                    errors.add(
+                        "steps",
+                        format!(
+                            "Step {} uses \"{}\", which isn't one of the ingredients.",
+                            step, name
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    // The values below are only meaningful for a form that validated.
+
+    pub fn name(&self) -> String {
+        self.name.trim().to_string()
+    }
+
+    pub fn servings(&self) -> Option<u32> {
+        whole_number(&self.servings, 1, SERVINGS_MAX).ok().flatten()
+    }
+
+    // Prep, cook and total.
+    pub fn minutes(&self) -> [Option<u32>; 3] {
+        [&self.prep_minutes, &self.cook_minutes, &self.total_minutes]
+            .map(|value| whole_number(value, 0, MINUTES_MAX).ok().flatten())
+    }
+
+    pub fn ingredient_lines(&self) -> Vec<IngredientLine> {
+        self.ingredients
+            .iter()
+            .map(|row| IngredientLine {
+                ingredient: row.ingredient.trim().to_string(),
+                quantity: parse_quantity(&row.quantity),
+                unit: optional(&row.unit),
+                note: optional(&row.note),
+            })
+            .collect()
+    }
+
+    pub fn step_lines(&self) -> Vec<StepLine> {
+        self.steps
+            .iter()
+            .map(|row| StepLine {
+                text: row.text.trim().to_string(),
+                // The timer is entered in minutes.
+                timer_seconds: parse_quantity(&row.timer_minutes)
+                    .map(|minutes| (minutes * 60.0).round() as u32),
+                ingredients: row.ingredient_names(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forms;
+
+    // Rows as a browser sends them, one value per input.
+    fn valid() -> RecipeForm {
+        let body = "name=+Pancakes+&servings=4&prep_minutes=10&cook_minutes=\
+            &quantity=1+1%2F2&unit=cup&ingredient=flour&note=sifted\
+            &quantity=&unit=&ingredient=milk&note=\
+            &quantity=&unit=&ingredient=&note=\
+            &step_text=Mix.&step_timer=&step_ingredients=Flour%2C+milk\
+            &step_text=Fry.&step_timer=2.5&step_ingredients=\
+            &step_text=&step_timer=&step_ingredients=";
+        RecipeForm::from_form(&forms::parse_urlencoded(body).unwrap())
+    }
+
+    #[test]
+    fn reads_the_form_without_blank_rows() {
+        let form = valid();
+        assert_eq!(form.ingredients.len(), 2);
+        assert_eq!(form.ingredients[0].quantity, "1 1/2");
+        assert_eq!(form.steps.len(), 2);
+        assert!(form.validate().is_empty());
+        assert_eq!(form.name(), "Pancakes");
+        assert_eq!(form.servings(), Some(4));
+        assert_eq!(form.minutes(), [Some(10), None, None]);
+        let lines = form.ingredient_lines();
+        assert_eq!(lines[0].quantity, Some(1.5));
+        assert_eq!(lines[1].unit, None);
+        assert_eq!(form.step_lines()[1].timer_seconds, Some(150));
+    }
+
+    #[test]
+    fn finds_mistakes_by_field() {
+        let mut form = valid();
+        form.name = "  ".to_string();
+        form.servings = "0".to_string();
+        form.total_minutes = "soon".to_string();
+        form.description = "x".repeat(DESCRIPTION_MAX + 1);
+        form.ingredients[1].quantity = "a lot".to_string();
+        form.ingredients.push(IngredientRow {
+            note: "to taste".to_string(),
+            ..IngredientRow::default()
+        });
+        form.steps[0].ingredients = "flour, eggs".to_string();
+        form.steps.push(StepRow {
+            timer_minutes: "5".to_string(),
+            ..StepRow::default()
+        });
+        let errors = form.validate();
+        for field in FIELDS {
+            assert!(!errors.html(field).is_empty(), "{}", field);
+        }
+        assert_eq!(errors.messages("minutes").len(), 1);
+        assert_eq!(
+            errors.messages("ingredients"),
+            vec![
+                "Line 2: \"a lot\" isn't an amount, use e.g. 2, 0.5 or 1 1/2.",
+                "Line 3 has an amount or note but no ingredient."
+            ]
+        );
+        assert_eq!(
+            errors.messages("steps"),
+            vec![
+                "Step 1 uses \"eggs\", which isn't one of the ingredients.",
+                "Step 3 has no text."
+            ]
+        );
+    }
+
+    #[test]
+    fn needs_an_ingredient() {
+        let mut form = valid();
+        form.ingredients.clear();
+        form.steps.clear();
+        assert_eq!(
+            form.validate().messages("ingredients"),
+            vec!["Add at least one ingredient."]
+        );
+    }
+
+    #[test]
+    fn escapes_messages() {
+        let mut errors = FieldErrors::default();
+        errors.add("name", "A recipe named \"<b>\" already exists.");
+        assert_eq!(
+            errors.html("name"),
+            "<div class=\"form-error\">A recipe named &quot;&lt;b&gt;&quot; already exists.</div>"
+        );
+        assert_eq!(errors.html("steps"), "");
+    }
+}
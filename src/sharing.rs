@@ -0,0 +1,373 @@
+// Who can see and change a recipe. A recipe belongs to the user who added
+// it and is one of
+//
+// - private: only the owner sees it;
+// - shared with a group: the owner and the group's members see and edit it;
+// - public: every user can read it, and so can anyone with its link at
+//   /shared/{token}, without logging in.
+//
+// Only the owner deletes a recipe or changes who it's shared with. Recipes
+// from before there were accounts, and ones imported on the command line,
+// have no owner and are open to every user, as before. The pantry,
+// shopping lists and meal plan belong to the whole household.
+//
+// Groups are managed from the command line:
+//
+//     recipe-helper group add GROUP USER
+//     recipe-helper group remove GROUP USER
+
+use crate::users::{new_token, User};
+use crate::{get_con, migrations};
+use rusqlite::{named_params, Connection, OptionalExtension};
+
+// SQL condition for the recipes `r` that the user `:user_id` can see.
+pub const VISIBLE: &str = "(r.owner_id IS NULL OR r.owner_id = :user_id
+    OR r.visibility = 'public'
+    OR (r.visibility = 'group' AND r.group_id IN
+        (SELECT group_id FROM group_members WHERE user_id = :user_id)))";
+
+// Like `VISIBLE`, for the recipes the user can change: like `can_edit`.
+pub const EDITABLE: &str = "(r.owner_id IS NULL OR r.owner_id = :user_id
+    OR (r.visibility = 'group' AND r.group_id IN
+        (SELECT group_id FROM group_members WHERE user_id = :user_id)))";
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Visibility {
+    #[default]
+    Private,
+    Group(usize),
+    Public,
+}
+
+impl Visibility {
+    // From the `visibility` and `group_id` columns.
+    pub fn from_columns(kind: &str, group_id: Option<usize>) -> Visibility {
+        match (kind, group_id) {
+            ("public", _) => Visibility::Public,
+            ("group", Some(group_id)) => Visibility::Group(group_id),
+            _ => Visibility::Private,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Visibility::Private => "private",
+            Visibility::Group(_) => "group",
+            Visibility::Public => "public",
+        }
+    }
+
+    pub fn group_id(&self) -> Option<usize> {
+        match self {
+            Visibility::Group(group_id) => Some(*group_id),
+            _ => None,
+        }
+    }
+
+    // As chosen on the form: "private", "public" or "group:ID".
+    pub fn parse(value: &str) -> Option<Visibility> {
+        match value.split_once(':') {
+            Some(("group", id)) => id.parse().ok().map(Visibility::Group),
+            _ => match value {
+                "private" => Some(Visibility::Private),
+                "public" => Some(Visibility::Public),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn form_value(&self) -> String {
+        match self {
+            Visibility::Group(group_id) => format!("group:{}", group_id),
+            _ => self.kind().to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct Sharing {
+    pub owner_id: Option<usize>,
+    pub visibility: Visibility,
+    // The secret part of a public recipe's link.
+    pub public_token: Option<String>,
+}
+
+impl Sharing {
+    pub fn owned_by(user: &User) -> Sharing {
+        Sharing {
+            owner_id: Some(user.id),
+            ..Sharing::default()
+        }
+    }
+
+    pub fn can_view(&self, con: &Connection, user: &User) -> rusqlite::Result<bool> {
+        Ok(self.visibility == Visibility::Public || self.can_edit(con, user)?)
+    }
+
+    pub fn can_edit(&self, con: &Connection, user: &User) -> rusqlite::Result<bool> {
+        if self.can_manage(user) {
+            return Ok(true);
+        }
+        match self.visibility.group_id() {
+            Some(group_id) => is_member(con, user.id, group_id),
+            None => Ok(false),
+        }
+    }
+
+    // Deleting the recipe and changing who it's shared with.
+    pub fn can_manage(&self, user: &User) -> bool {
+        self.owner_id.is_none_or(|owner_id| owner_id == user.id)
+    }
+
+    // A recipe gets a new link whenever it's made public, so taking it
+    // private and public again stops the old link from working.
+    pub fn set_visibility(&mut self, visibility: Visibility) {
+        if visibility == self.visibility {
+            return;
+        }
+        self.visibility = visibility;
+        self.public_token = match visibility {
+            Visibility::Public => Some(new_token()),
+            _ => None,
+        };
+    }
+}
+
+pub fn is_member(con: &Connection, user_id: usize, group_id: usize) -> rusqlite::Result<bool> {
+    let member = con
+        .query_row(
+            "SELECT 1 FROM group_members WHERE user_id = :user_id AND group_id = :group_id",
+            named_params! { ":user_id": user_id, ":group_id": group_id },
+            |_| Ok(()),
+        )
+        .optional()?;
+    Ok(member.is_some())
+}
+
+// The groups the user is in, by name.
+pub fn groups_of(con: &Connection, user: &User) -> rusqlite::Result<Vec<(usize, String)>> {
+    let mut stmt = con.prepare(
+        "SELECT g.id, g.name FROM user_groups AS g
+         JOIN group_members AS m ON m.group_id = g.id
+         WHERE m.user_id = :user_id ORDER BY g.name",
+    )?;
+    let groups = stmt
+        .query_map(named_params! { ":user_id": user.id }, |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect();
+    groups
+}
+
+pub fn group_name(con: &Connection, group_id: usize) -> rusqlite::Result<Option<String>> {
+    con.query_row(
+        "SELECT name FROM user_groups WHERE id = :id",
+        named_params! { ":id": group_id },
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+// The id of the public recipe with this link.
+pub fn recipe_id_by_token(token: &str) -> rusqlite::Result<Option<usize>> {
+    get_con()?
+        .query_row(
+            "SELECT id FROM recipes WHERE public_token = :token AND visibility = 'public'",
+            named_params! { ":token": token },
+            |row| row.get(0),
+        )
+        .optional()
+}
+
+fn change_membership(
+    con: &Connection,
+    action: &str,
+    group: &str,
+    user: &str,
+) -> std::result::Result<String, String> {
+    let user_id: usize = con
+        .query_row(
+            "SELECT id FROM users WHERE name = :name",
+            named_params! { ":name": user },
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?
+        .ok_or(format!("there is no user {}", user))?;
+    match action {
+        "add" => {
+            con.execute(
+                "INSERT OR IGNORE INTO user_groups (name) VALUES (:name)",
+                named_params! { ":name": group },
+            )
+            .map_err(|e| e.to_string())?;
+            con.execute(
+                "INSERT OR IGNORE INTO group_members (group_id, user_id)
+                 SELECT id, :user_id FROM user_groups WHERE name = :name",
+                named_params! { ":name": group, ":user_id": user_id },
+            )
+            .map_err(|e| e.to_string())?;
+            Ok(format!("added {} to {}", user, group))
+        }
+        _ => {
+            let removed = con
+                .execute(
+                    "DELETE FROM group_members WHERE user_id = :user_id
+                     AND group_id = (SELECT id FROM user_groups WHERE name = :name)",
+                    named_params! { ":name": group, ":user_id": user_id },
+                )
+                .map_err(|e| e.to_string())?;
+            match removed {
+                0 => Err(format!("{} isn't in {}", user, group)),
+                _ => Ok(format!("removed {} from {}", user, group)),
+            }
+        }
+    }
+}
+
+pub fn group_command(args: &[String]) {
+    let (Some(action @ ("add" | "remove")), Some(group), Some(user), None) = (
+        args.first().map(String::as_str),
+        args.get(1),
+        args.get(2),
+        args.get(3),
+    ) else {
+        eprintln!("usage: recipe-helper group add|remove GROUP USER");
+        std::process::exit(2);
+    };
+    let mut con = get_con().unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    });
+    if let Err(e) = migrations::migrate(&mut con) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+    match change_membership(&con, action, group.trim(), user.trim()) {
+        Ok(message) => println!("{}", message),
+        Err(message) => {
+            eprintln!("error: {}", message);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: usize) -> User {
+        User {
+            id,
+            name: format!("user {}", id),
+        }
+    }
+
+    fn sharing(owner_id: Option<usize>, visibility: Visibility) -> Sharing {
+        Sharing {
+            owner_id,
+            visibility,
+            public_token: None,
+        }
+    }
+
+    // Ada (1) and Bob (2) are in the family group, Cy (3) isn't.
+    fn database() -> Connection {
+        let mut con = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut con).unwrap();
+        con.execute_batch(
+            "insert into users (id, name, password_hash) values
+                (1, 'ada', ''), (2, 'bob', ''), (3, 'cy', '');
+             insert into recipes (id, name, owner_id, visibility, group_id) values
+                (1, 'Ada''s secret', 1, 'private', null),
+                (2, 'Family stew', 1, 'group', null),
+                (3, 'Open pie', 1, 'public', null),
+                (4, 'Old soup', null, 'private', null);",
+        )
+        .unwrap();
+        change_membership(&con, "add", "family", "ada").unwrap();
+        change_membership(&con, "add", "family", "bob").unwrap();
+        con.execute(
+            "update recipes set group_id = (select id from user_groups) where id = 2",
+            [],
+        )
+        .unwrap();
+        con
+    }
+
+    fn visible_ids(con: &Connection, user_id: usize) -> Vec<usize> {
+        let mut stmt = con
+            .prepare(&format!(
+                "SELECT r.id FROM recipes AS r WHERE {} ORDER BY r.id",
+                VISIBLE
+            ))
+            .unwrap();
+        let ids = stmt
+            .query_map(named_params! { ":user_id": user_id }, |row| row.get(0))
+            .unwrap()
+            .map(|x| x.unwrap())
+            .collect();
+        ids
+    }
+
+    #[test]
+    fn lists_only_visible_recipes() {
+        let con = database();
+        assert_eq!(visible_ids(&con, 1), vec![1, 2, 3, 4]);
+        assert_eq!(visible_ids(&con, 2), vec![2, 3, 4]);
+        assert_eq!(visible_ids(&con, 3), vec![3, 4]);
+    }
+
+    #[test]
+    fn keeps_private_recipes_to_the_owner() {
+        let con = database();
+        let (ada, bob) = (user(1), user(2));
+        let private = sharing(Some(1), Visibility::Private);
+        assert!(private.can_view(&con, &ada).unwrap());
+        assert!(private.can_edit(&con, &ada).unwrap());
+        assert!(!private.can_view(&con, &bob).unwrap());
+        assert!(!private.can_edit(&con, &bob).unwrap());
+        assert!(!private.can_manage(&bob));
+    }
+
+    #[test]
+    fn shares_with_groups_and_links() {
+        let con = database();
+        let (ada, bob, cy) = (user(1), user(2), user(3));
+        let group_id = groups_of(&con, &bob).unwrap()[0].0;
+        let shared = sharing(Some(1), Visibility::Group(group_id));
+        assert!(shared.can_edit(&con, &bob).unwrap());
+        assert!(!shared.can_manage(&bob));
+        assert!(!shared.can_view(&con, &cy).unwrap());
+
+        let mut public = sharing(Some(1), Visibility::Private);
+        public.set_visibility(Visibility::Public);
+        let token = public.public_token.clone().unwrap();
+        assert!(public.can_view(&con, &cy).unwrap());
+        assert!(!public.can_edit(&con, &cy).unwrap());
+        public.set_visibility(Visibility::Private);
+        assert_eq!(public.public_token, None);
+        public.set_visibility(Visibility::Public);
+        assert_ne!(public.public_token, Some(token));
+
+        let old = sharing(None, Visibility::Private);
+        assert!(old.can_edit(&con, &cy).unwrap());
+        assert!(old.can_manage(&ada));
+    }
+
+    #[test]
+    fn parses_form_values() {
+        for visibility in [
+            Visibility::Private,
+            Visibility::Group(3),
+            Visibility::Public,
+        ] {
+            assert_eq!(
+                Visibility::parse(&visibility.form_value()),
+                Some(visibility)
+            );
+        }
+        assert_eq!(Visibility::parse("group:x"), None);
+        assert_eq!(Visibility::from_columns("group", None), Visibility::Private);
+    }
+}
@@ -0,0 +1,580 @@
+// Shopping lists built from several recipes. Identical ingredients are
+// merged, amounts with compatible units are added up and whatever is in the
+// pantry is subtracted. Lists are stored so they can be checked off while
+// shopping and exported as plain text or Markdown.
+
+use crate::dates::Date;
+use crate::db;
+use crate::errors::Result;
+use crate::pantry::{get_pantry, PantryItem};
+use crate::units::{self, UnitSystem};
+use crate::users::{self, User};
+use crate::{
+    escape_html, get_con, get_recipe_by_id, get_recipes, get_usize, not_found, return_redirect,
+    serve_bytes, serve_html, sharing, template::Template, Ingredient, RecipeIngredient,
+};
+use rusqlite::{named_params, Connection, OptionalExtension};
+use tiny_http::{Method, Request, ResponseBox};
+
+struct ShoppingItem {
+    id: usize,
+    line: RecipeIngredient,
+    checked: bool,
+}
+
+struct ShoppingList {
+    id: usize,
+    name: String,
+    created_on: String,
+    recipes: Vec<String>,
+    items: Vec<ShoppingItem>,
+}
+
+// Everything needed of one ingredient, split by kind of amount.
+struct Needed {
+    ingredient: Ingredient,
+    // Used without an amount, e.g. "salt".
+    without_amount: bool,
+    amounts: Vec<Amount>,
+}
+
+// A running total. Known units are added up in grams or millilitres, other
+// units only with the same unit.
+struct Amount {
+    key: String,
+    in_base_units: bool,
+    total: f64,
+    units: Vec<String>,
+}
+
+// Amounts with the same key can be added up. Returns the key, whether the
+// amount was converted to grams or millilitres, and the converted amount.
+fn amount_key(quantity: f64, unit: Option<&str>) -> (String, bool, f64) {
+    match unit.and_then(|u| units::to_base(quantity, u)) {
+        Some((base, symbol)) => (symbol.to_string(), true, base),
+        None => (unit.unwrap_or_default().to_lowercase(), false, quantity),
+    }
+}
+
+impl Needed {
+    fn amount(&mut self, quantity: f64, unit: Option<&str>) -> &mut Amount {
+        let (key, in_base_units, total) = amount_key(quantity, unit);
+        let index = match self.amounts.iter().position(|a| a.key == key) {
+            Some(index) => index,
+            None => {
+                self.amounts.push(Amount {
+                    key,
+                    in_base_units,
+                    total: 0.0,
+                    units: vec![],
+                });
+                self.amounts.len() - 1
+            }
+        };
+        let amount = &mut self.amounts[index];
+        amount.total += total;
+        amount
+    }
+
+    fn subtract(&mut self, pantry: &PantryItem) {
+        let Some(quantity) = pantry.quantity else {
+            // In the pantry without an amount means we have enough.
+            self.without_amount = false;
+            self.amounts.clear();
+            return;
+        };
+        self.without_amount = false;
+        let (key, _, have) = amount_key(quantity, pantry.unit.as_deref());
+        if let Some(amount) = self.amounts.iter_mut().find(|a| a.key == key) {
+            amount.total -= have;
+        }
+        self.amounts.retain(|a| a.total > 1e-9);
+    }
+
+    fn into_lines(self) -> Vec<RecipeIngredient> {
+        let mut lines = vec![];
+        for amount in self.amounts.iter() {
+            let (quantity, unit) = amount.display();
+            lines.push(RecipeIngredient {
+                ingredient: self.ingredient.clone(),
+                quantity: Some(quantity),
+                unit,
+                note: None,
+            });
+        }
+        if self.without_amount && lines.is_empty() {
+            lines.push(RecipeIngredient {
+                ingredient: self.ingredient,
+                quantity: None,
+                unit: None,
+                note: None,
+            });
+        }
+        lines
+    }
+}
+
+impl Amount {
+    // Amounts that all used the same unit (however it was spelled) stay in
+    // it, mixed ones are shown in whatever metric unit fits.
+    fn display(&self) -> (f64, Option<String>) {
+        if !self.in_base_units {
+            return (self.total, self.units.first().cloned());
+        }
+        let first = &self.units[0];
+        if self
+            .units
+            .iter()
+            .all(|u| units::to_base(1.0, u) == units::to_base(1.0, first))
+        {
+            if let Some(quantity) = units::from_base(self.total, first) {
+                return (quantity, Some(first.clone()));
+            }
+        }
+        let (quantity, unit) = units::convert(self.total, &self.key, UnitSystem::Metric);
+        (quantity, Some(unit))
+    }
+}
+
+// Merges the ingredient lines of several recipes into one line per
+// ingredient and kind of amount, minus what the pantry already has.
+fn consolidate(lines: Vec<RecipeIngredient>, pantry: &[PantryItem]) -> Vec<RecipeIngredient> {
+    let mut needed: Vec<Needed> = vec![];
+    for line in lines {
+        let index = match needed
+            .iter()
+            .position(|n| n.ingredient.id == line.ingredient.id)
+        {
+            Some(index) => index,
+            None => {
+                needed.push(Needed {
+                    ingredient: line.ingredient.clone(),
+                    without_amount: false,
+                    amounts: vec![],
+                });
+                needed.len() - 1
+            }
+        };
+        let entry = &mut needed[index];
+        match line.quantity {
+            Some(quantity) => {
+                let amount = entry.amount(quantity, line.unit.as_deref());
+                if let Some(unit) = line.unit {
+                    amount.units.push(unit);
+                }
+            }
+            None => entry.without_amount = true,
+        }
+    }
+    for entry in needed.iter_mut() {
+        if let Some(item) = pantry
+            .iter()
+            .find(|p| p.ingredient.id == entry.ingredient.id)
+        {
+            entry.subtract(item);
+        }
+    }
+    needed.sort_by(|a, b| a.ingredient.name.cmp(&b.ingredient.name));
+    needed.into_iter().flat_map(Needed::into_lines).collect()
+}
+
+fn get_shopping_lists() -> rusqlite::Result<Vec<(usize, String, String)>> {
+    let con = get_con()?;
+    let mut stmt =
+        con.prepare("SELECT id, name, created_on FROM shopping_lists ORDER BY id DESC")?;
+    let lists = stmt
+        .query_map((), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect();
+    lists
+}
+
+// Lists are shared, but only the recipes `user` can see are named.
+fn get_shopping_list(
+    con: &Connection,
+    id: usize,
+    user: &User,
+) -> rusqlite::Result<Option<ShoppingList>> {
+    let list = con
+        .query_row(
+            "SELECT id, name, created_on FROM shopping_lists WHERE id = :id",
+            named_params! { ":id": id },
+            |row| {
+                Ok(ShoppingList {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    created_on: row.get(2)?,
+                    recipes: vec![],
+                    items: vec![],
+                })
+            },
+        )
+        .optional()?;
+    let Some(mut list) = list else {
+        return Ok(None);
+    };
+    let mut stmt = con.prepare(&format!(
+        "SELECT r.name FROM shopping_list_recipes AS sr
+         JOIN recipes AS r ON r.id = sr.recipe_id
+         WHERE sr.list_id = :id AND {} ORDER BY r.name",
+        sharing::VISIBLE
+    ))?;
+    list.recipes = stmt
+        .query_map(named_params! { ":id": id, ":user_id": user.id }, |row| {
+            row.get(0)
+        })?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    let mut stmt = con.prepare(
+        "SELECT s.id, i.id, i.name, s.quantity, s.unit, s.checked
+         FROM shopping_list_items AS s
+         JOIN ingredients AS i ON i.id = s.ingredient_id
+         WHERE s.list_id = :id ORDER BY s.id",
+    )?;
+    list.items = stmt
+        .query_map(named_params! { ":id": id }, |row| {
+            Ok(ShoppingItem {
+                id: row.get(0)?,
+                line: RecipeIngredient {
+                    ingredient: Ingredient {
+                        id: row.get(1)?,
+                        name: row.get(2)?,
+                    },
+                    quantity: row.get(3)?,
+                    unit: row.get(4)?,
+                    note: None,
+                },
+                checked: row.get(5)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<ShoppingItem>>>()?;
+    Ok(Some(list))
+}
+
+impl ShoppingList {
+    fn to_text(&self) -> String {
+        let mut text = format!("{} ({})\n", self.name, self.created_on);
+        for item in self.items.iter() {
+            let mark = if item.checked { "x" } else { " " };
+            text += format!("[{}] {}\n", mark, item.line.render()).as_str();
+        }
+        text
+    }
+
+    fn to_markdown(&self) -> String {
+        let mut text = format!("# {}\n\n", self.name);
+        if !self.recipes.is_empty() {
+            text += format!("For: {}\n\n", self.recipes.join(", ")).as_str();
+        }
+        for item in self.items.iter() {
+            let mark = if item.checked { "x" } else { " " };
+            text += format!("- [{}] {}\n", mark, item.line.render()).as_str();
+        }
+        text
+    }
+}
+
+// Entry point for everything under /shopping.
+pub fn handle_shopping(request: &mut Request, user: &User) -> Result<ResponseBox> {
+    let url = request.url().to_string();
+    let path = url.split('?').next().unwrap_or_default();
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<&str>>();
+    let method = request.method().clone();
+    if let (Method::Post, ["shopping", _, ..]) = (&method, segments.as_slice()) {
+        users::read_checked_form(request)?;
+    }
+    match (method, segments.as_slice()) {
+        (Method::Get, ["shopping"]) => lists_page(request, user),
+        (Method::Post, ["shopping"]) => create_list_post(request, user),
+        (Method::Get, ["shopping", file]) => {
+            // `/shopping/{id}`, or the list as text in `{id}.txt` or `{id}.md`.
+            let (id, extension) = file.split_once('.').unwrap_or((file, ""));
+            let list = match get_usize(id) {
+                Some(id) => get_shopping_list(&*get_con()?, id, user)?,
+                None => None,
+            };
+            let Some(list) = list else {
+                return Err(not_found("Shopping list not found."));
+            };
+            match extension {
+                "" => list_page(request, list),
+                "txt" => serve_bytes(list.to_text().as_bytes(), "text/plain; charset=utf-8"),
+                "md" => serve_bytes(
+                    list.to_markdown().as_bytes(),
+                    "text/markdown; charset=utf-8",
+                ),
+                _ => Err(not_found("Shopping list not found.")),
+            }
+        }
+        (Method::Post, ["shopping", id, "toggle", item]) => {
+            if let (Some(id), Some(item)) = (get_usize(id), get_usize(item)) {
+                get_con()?.execute(
+                    "UPDATE shopping_list_items SET checked = NOT checked
+                     WHERE id = :item AND list_id = :id",
+                    named_params! { ":id": id, ":item": item },
+                )?;
+            }
+            return_redirect(format!("/shopping/{}", id))
+        }
+        (Method::Post, ["shopping", id, "delete"]) => {
+            if let Some(id) = get_usize(id) {
+                let mut con = get_con()?;
+                let tx = db::write_transaction(&mut con)?;
+                for table in ["shopping_list_items", "shopping_list_recipes"] {
+                    tx.execute(
+                        format!("DELETE FROM {} WHERE list_id = :id", table).as_str(),
+                        named_params! { ":id": id },
+                    )?;
+                }
+                tx.execute(
+                    "DELETE FROM shopping_lists WHERE id = :id",
+                    named_params! { ":id": id },
+                )?;
+                tx.commit()?;
+            }
+            return_redirect("/shopping".to_string())
+        }
+        _ => return_redirect("/shopping".to_string()),
+    }
+}
+
+fn lists_page(request: &Request, user: &User) -> Result<ResponseBox> {
+    let mut page = Template::page("shopping.html");
+    let lists = get_shopping_lists()?
+        .iter()
+        .map(|(id, name, created_on)| {
+            format!(
+                "<div><a href=\"/shopping/{}\">{}</a> ({})</div>",
+                id,
+                escape_html(name),
+                created_on
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("");
+    let recipes = get_recipes(user)?
+        .iter()
+        .map(|recipe| {
+            format!(
+                "<label class=\"check\"><input type=\"checkbox\" name=\"recipes\" value=\"{}\" /> {}</label>",
+                recipe.id,
+                escape_html(&recipe.name)
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("");
+    page.set_html("lists", lists);
+    page.set_html("recipes", recipes);
+    page.set("name", format!("Shopping {}", Date::today()));
+    serve_html(request, page)
+}
+
+fn create_list_post(request: &mut Request, user: &User) -> Result<ResponseBox> {
+    let param_map = users::read_checked_form(request)?;
+    let recipe_ids = param_map
+        .get_all("recipes")
+        .into_iter()
+        .filter_map(get_usize)
+        .collect::<Vec<usize>>();
+    let mut recipes = vec![];
+    for id in recipe_ids {
+        recipes.extend(get_recipe_by_id(id, user)?);
+    }
+    if recipes.is_empty() {
+        return return_redirect("/shopping".to_string());
+    }
+    let name = param_map
+        .get("name")
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| format!("Shopping {}", Date::today()));
+    let pantry = match param_map.contains("subtract_pantry") {
+        true => get_pantry(&*get_con()?)?,
+        false => vec![],
+    };
+    let recipe_ids = recipes.iter().map(|r| r.id).collect::<Vec<usize>>();
+    let lines = consolidate(
+        recipes.into_iter().flat_map(|r| r.ingredients).collect(),
+        &pantry,
+    );
+
+    let mut con = get_con()?;
+    let tx = db::write_transaction(&mut con)?;
+    tx.execute(
+        "INSERT INTO shopping_lists (name, created_on) VALUES (:name, :created_on)",
+        named_params! { ":name": name, ":created_on": Date::today().to_string() },
+    )?;
+    let list_id = tx.last_insert_rowid();
+    for recipe_id in recipe_ids {
+        tx.execute(
+            "INSERT OR IGNORE INTO shopping_list_recipes (list_id, recipe_id)
+             VALUES (:list_id, :recipe_id)",
+            named_params! { ":list_id": list_id, ":recipe_id": recipe_id },
+        )?;
+    }
+    for line in lines {
+        tx.execute(
+            "INSERT INTO shopping_list_items (list_id, ingredient_id, quantity, unit)
+             VALUES (:list_id, :ingredient_id, :quantity, :unit)",
+            named_params! {
+                ":list_id": list_id,
+                ":ingredient_id": line.ingredient.id,
+                ":quantity": line.quantity,
+                ":unit": line.unit,
+            },
+        )?;
+    }
+    tx.commit()?;
+    return_redirect(format!("/shopping/{}", list_id))
+}
+
+fn list_page(request: &Request, list: ShoppingList) -> Result<ResponseBox> {
+    let mut page = Template::page("shopping-list.html");
+    let csrf_field = users::csrf_field(request)?;
+    let items = list
+        .items
+        .iter()
+        .map(|item| {
+            let class = if item.checked { "item checked" } else { "item" };
+            let mark = if item.checked { "☑" } else { "☐" };
+            format!(
+                "<form action=\"/shopping/{}/toggle/{}\" method=\"POST\">
+                    {}
+                    <button type=\"submit\" class=\"{}\">{} {}</button>
+                </form>",
+                list.id,
+                item.id,
+                csrf_field,
+                class,
+                mark,
+                escape_html(&item.line.render())
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("");
+    page.set("id", list.id);
+    page.set("name", &list.name);
+    page.set("recipes", list.recipes.join(", "));
+    page.set_html("items", items);
+    serve_html(request, page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations;
+
+    fn line(id: usize, name: &str, quantity: Option<f64>, unit: Option<&str>) -> RecipeIngredient {
+        RecipeIngredient {
+            ingredient: Ingredient {
+                id,
+                name: name.to_string(),
+            },
+            quantity,
+            unit: unit.map(|u| u.to_string()),
+            note: None,
+        }
+    }
+
+    fn pantry(id: usize, name: &str, quantity: Option<f64>, unit: Option<&str>) -> PantryItem {
+        PantryItem {
+            ingredient: Ingredient {
+                id,
+                name: name.to_string(),
+            },
+            quantity,
+            unit: unit.map(|u| u.to_string()),
+            expires_on: None,
+        }
+    }
+
+    fn rendered(lines: Vec<RecipeIngredient>) -> Vec<String> {
+        lines.iter().map(|l| l.render()).collect()
+    }
+
+    #[test]
+    fn merges_identical_ingredients() {
+        let lines = vec![
+            line(1, "flour", Some(200.0), Some("g")),
+            line(2, "eggs", Some(2.0), None),
+            line(1, "flour", Some(300.0), Some("g")),
+            line(2, "eggs", Some(1.0), None),
+            line(3, "salt", None, None),
+            line(3, "salt", None, None),
+        ];
+        assert_eq!(
+            rendered(consolidate(lines, &[])),
+            vec!["3 eggs", "500 g flour", "salt"]
+        );
+    }
+
+    #[test]
+    fn sums_compatible_units() {
+        let lines = vec![
+            line(1, "flour", Some(1.0), Some("kg")),
+            line(1, "flour", Some(500.0), Some("g")),
+            line(2, "milk", Some(1.0), Some("cup")),
+            line(2, "milk", Some(2.0), Some("cups")),
+            line(3, "garlic", Some(2.0), Some("cloves")),
+            line(3, "garlic", Some(1.0), Some("tbsp")),
+        ];
+        assert_eq!(
+            rendered(consolidate(lines, &[])),
+            vec![
+                "1.5 kg flour",
+                "2 cloves garlic",
+                "1 tbsp garlic",
+                "3 cup milk"
+            ]
+        );
+    }
+
+    #[test]
+    fn subtracts_the_pantry() {
+        let lines = vec![
+            line(1, "flour", Some(500.0), Some("g")),
+            line(2, "milk", Some(1.0), Some("l")),
+            line(3, "salt", None, None),
+            line(4, "eggs", Some(2.0), None),
+        ];
+        let have = vec![
+            pantry(1, "flour", Some(0.2), Some("kg")),
+            pantry(2, "milk", Some(2.0), Some("l")),
+            pantry(3, "salt", None, None),
+            pantry(4, "eggs", Some(1.0), Some("dozen")),
+        ];
+        assert_eq!(
+            rendered(consolidate(lines, &have)),
+            vec!["2 eggs", "300 g flour"]
+        );
+    }
+
+    // Ada (1) made a list from her private soup and Bob's (2) public cake.
+    #[test]
+    fn names_only_recipes_the_user_can_see() {
+        let mut con = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut con).unwrap();
+        con.execute_batch(
+            "insert into users (id, name, password_hash) values (1, 'ada', ''), (2, 'bob', '');
+             insert into recipes (id, name, owner_id, visibility) values
+                 (1, 'Secret soup', 1, 'private'), (2, 'Cake', 2, 'public');
+             insert into shopping_lists (id, name, created_on) values (1, 'Weekend', '2024-03-04');
+             insert into shopping_list_recipes (list_id, recipe_id) values (1, 1), (1, 2);",
+        )
+        .unwrap();
+        let user = |id: usize, name: &str| User {
+            id,
+            name: name.to_string(),
+        };
+        let list = get_shopping_list(&con, 1, &user(2, "bob"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(list.recipes, vec!["Cake"]);
+        assert!(!list.to_markdown().contains("Secret soup"));
+        let list = get_shopping_list(&con, 1, &user(1, "ada"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(list.recipes, vec!["Cake", "Secret soup"]);
+    }
+}
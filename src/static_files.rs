@@ -0,0 +1,336 @@
+// Serves the scripts and styles under /static/. Only the files listed in
+// `SERVED` are served; any other name is a 404. Responses have an ETag and
+// Last-Modified so the browser can check back and get a 304, and are sent
+// brotli or gzip compressed when the browser accepts it. The compressed
+// variants are made once at startup, so no request waits for them.
+
+use crate::errors::Result;
+use crate::{assets, find_header, not_found};
+use brotli::enc::BrotliEncoderParams;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::Write;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, Request, Response, ResponseBox};
+
+const SERVED: &[&str] = &["jquery-3.7.0.min.js", "select2.min.css", "select2.min.js"];
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    // The name in Accept-Encoding and Content-Encoding.
+    fn name(&self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    fn compress(&self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Identity => bytes.to_vec(),
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], Compression::best());
+                encoder.write_all(bytes).expect("To gzip in memory");
+                encoder.finish().expect("To gzip in memory")
+            }
+            Encoding::Brotli => {
+                let mut compressed = vec![];
+                brotli::BrotliCompress(
+                    &mut &bytes[..],
+                    &mut compressed,
+                    &BrotliEncoderParams::default(),
+                )
+                .expect("To compress in memory");
+                compressed
+            }
+        }
+    }
+}
+
+// A served file in every encoding, along with the ETag of the original.
+struct Precompressed {
+    name: &'static str,
+    etag: String,
+    variants: Vec<(Encoding, Vec<u8>)>,
+}
+
+fn precompressed() -> &'static [Precompressed] {
+    static FILES: OnceLock<Vec<Precompressed>> = OnceLock::new();
+    FILES.get_or_init(|| {
+        SERVED
+            .iter()
+            .filter_map(|name| {
+                let bytes = assets::get(name)?;
+                Some(Precompressed {
+                    name,
+                    etag: etag(&bytes, Encoding::Identity),
+                    variants: [Encoding::Gzip, Encoding::Brotli]
+                        .into_iter()
+                        .map(|encoding| (encoding, encoding.compress(&bytes)))
+                        .collect(),
+                })
+            })
+            .collect()
+    })
+}
+
+// Compresses every served file, before the server takes requests.
+pub fn precompress() {
+    precompressed();
+}
+
+// The variant made at startup. A file in the assets directory that was
+// edited since is compressed for every request, which is fine while
+// working on it.
+fn compressed(name: &str, encoding: Encoding, bytes: &[u8]) -> Vec<u8> {
+    let etag = etag(bytes, Encoding::Identity);
+    precompressed()
+        .iter()
+        .filter(|file| file.name == name && file.etag == etag)
+        .flat_map(|file| file.variants.iter())
+        .find(|(variant, _)| *variant == encoding)
+        .map(|(_, compressed)| compressed.clone())
+        .unwrap_or_else(|| encoding.compress(bytes))
+}
+
+fn content_type(name: &str) -> &'static str {
+    match name.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("html") => "text/html; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        Some("woff") => "font/woff",
+        _ => "application/octet-stream",
+    }
+}
+
+// The best encoding the browser accepts, brotli first.
+fn choose_encoding(accept_encoding: &str) -> Encoding {
+    let accepted = accept_encoding
+        .split(',')
+        .filter_map(|part| {
+            let mut params = part.split(';');
+            let name = params.next()?.trim().to_lowercase();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((name, quality))
+        })
+        .collect::<Vec<(String, f32)>>();
+    let accepts = |encoding: Encoding| {
+        let quality = accepted
+            .iter()
+            .find(|(name, _)| name == encoding.name())
+            .or_else(|| accepted.iter().find(|(name, _)| name == "*"))
+            .map(|(_, quality)| *quality)
+            .unwrap_or_default();
+        quality > 0.0
+    };
+    [Encoding::Brotli, Encoding::Gzip]
+        .into_iter()
+        .find(|encoding| accepts(*encoding))
+        .unwrap_or(Encoding::Identity)
+}
+
+// FNV-1a of the content, quoted. Compressed variants get a suffix, as they
+// are different bytes.
+fn etag(bytes: &[u8], encoding: Encoding) -> String {
+    let hash = bytes.iter().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+    match encoding {
+        Encoding::Identity => format!("\"{:016x}\"", hash),
+        _ => format!("\"{:016x}-{}\"", hash, encoding.name()),
+    }
+}
+
+// Whether the browser's copy is still current. If-None-Match wins over
+// If-Modified-Since when both are sent.
+fn not_modified(
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+    etag: &str,
+    modified: Option<SystemTime>,
+) -> bool {
+    if let Some(tags) = if_none_match {
+        return tags
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+    match (if_modified_since.map(httpdate::parse_http_date), modified) {
+        (Some(Ok(since)), Some(modified)) => whole_seconds(modified) <= since,
+        _ => false,
+    }
+}
+
+// HTTP dates have no fractions of a second.
+fn whole_seconds(time: SystemTime) -> SystemTime {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    UNIX_EPOCH + Duration::from_secs(seconds)
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field, value).expect("That we didn't put any garbage in the headers")
+}
+
+pub fn handle_static(request: &Request) -> Result<ResponseBox> {
+    let path = request.url().split('?').next().unwrap_or_default();
+    let name = path.strip_prefix("/static/").unwrap_or_default();
+    let Some(bytes) = SERVED.contains(&name).then(|| assets::get(name)).flatten() else {
+        return Err(not_found("There's no such file."));
+    };
+    let header_value = |field: &str| {
+        find_header(request.headers(), field.to_string()).map(|h| h.value.as_str().to_string())
+    };
+    let encoding = choose_encoding(&header_value("Accept-Encoding").unwrap_or_default());
+    let etag_value = etag(&bytes, encoding);
+    let modified = assets::modified(name);
+    let mut headers = vec![
+        header("ETag", &etag_value),
+        header("Cache-Control", "no-cache"),
+        header("Vary", "Accept-Encoding"),
+    ];
+    if let Some(modified) = modified {
+        headers.push(header("Last-Modified", &httpdate::fmt_http_date(modified)));
+    }
+    if not_modified(
+        header_value("If-None-Match").as_deref(),
+        header_value("If-Modified-Since").as_deref(),
+        &etag_value,
+        modified,
+    ) {
+        let mut response = Response::empty(304);
+        for h in headers {
+            response.add_header(h);
+        }
+        return Ok(response.boxed());
+    }
+
+    let body = match encoding {
+        Encoding::Identity => bytes.to_vec(),
+        _ => compressed(name, encoding, &bytes),
+    };
+    let mut response = Response::from_data(body);
+    response.add_header(header("Content-Type", content_type(name)));
+    if encoding != Encoding::Identity {
+        response.add_header(header("Content-Encoding", encoding.name()));
+    }
+    for h in headers {
+        response.add_header(h);
+    }
+    Ok(response.boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn picks_the_accepted_encoding() {
+        assert_eq!(choose_encoding(""), Encoding::Identity);
+        assert_eq!(choose_encoding("gzip, deflate, br"), Encoding::Brotli);
+        assert_eq!(choose_encoding("gzip, br;q=0"), Encoding::Gzip);
+        assert_eq!(choose_encoding("*"), Encoding::Brotli);
+        assert_eq!(choose_encoding("*;q=0, identity"), Encoding::Identity);
+    }
+
+    #[test]
+    fn compresses() {
+        let text = "function f() { return 1; }\n".repeat(100);
+        let gzipped = Encoding::Gzip.compress(text.as_bytes());
+        let mut unzipped = String::new();
+        flate2::read::GzDecoder::new(&gzipped[..])
+            .read_to_string(&mut unzipped)
+            .unwrap();
+        assert_eq!(unzipped, text);
+        let brotlied = Encoding::Brotli.compress(text.as_bytes());
+        let mut decompressed = vec![];
+        brotli::BrotliDecompress(&mut &brotlied[..], &mut decompressed).unwrap();
+        assert_eq!(decompressed, text.as_bytes());
+        assert!(brotlied.len() < text.len() / 10);
+    }
+
+    #[test]
+    fn precompresses_every_served_file() {
+        assert_eq!(precompressed().len(), SERVED.len());
+        for name in SERVED {
+            let bytes = assets::get(name).unwrap();
+            let gzipped = compressed(name, Encoding::Gzip, &bytes);
+            let mut unzipped = vec![];
+            flate2::read::GzDecoder::new(&gzipped[..])
+                .read_to_end(&mut unzipped)
+                .unwrap();
+            assert_eq!(unzipped, bytes.to_vec());
+            let file = precompressed().iter().find(|f| f.name == *name).unwrap();
+            assert_eq!(
+                file.variants[1].1,
+                compressed(name, Encoding::Brotli, &bytes)
+            );
+        }
+        // Something else under a served name, like an edited file.
+        let edited = compressed("select2.min.js", Encoding::Gzip, b"edited");
+        let mut unzipped = vec![];
+        flate2::read::GzDecoder::new(&edited[..])
+            .read_to_end(&mut unzipped)
+            .unwrap();
+        assert_eq!(unzipped, b"edited");
+    }
+
+    #[test]
+    fn checks_freshness() {
+        let tag = etag(b"body", Encoding::Gzip);
+        assert_ne!(tag, etag(b"body", Encoding::Identity));
+        assert!(not_modified(Some(&tag), None, &tag, None));
+        assert!(not_modified(
+            Some(&format!("\"x\", W/{}", tag)),
+            None,
+            &tag,
+            None
+        ));
+        assert!(!not_modified(Some("\"x\""), None, &tag, None));
+
+        let modified = UNIX_EPOCH + Duration::from_millis(784_111_777_500);
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert!(not_modified(None, Some(date), &tag, Some(modified)));
+        let later = modified + Duration::from_secs(1);
+        assert!(!not_modified(None, Some(date), &tag, Some(later)));
+        assert!(!not_modified(
+            Some("\"x\""),
+            Some(date),
+            &tag,
+            Some(modified)
+        ));
+    }
+
+    #[test]
+    fn knows_content_types() {
+        assert_eq!(content_type("select2.min.css"), "text/css; charset=utf-8");
+        assert_eq!(
+            content_type("jquery-3.7.0.min.js"),
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(content_type("notes"), "application/octet-stream");
+    }
+}
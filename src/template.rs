@@ -0,0 +1,90 @@
+// Fills the `{name}` placeholders of the HTML templates. Values are
+// escaped, unless they are set with `set_html`, which is only for markup
+// we built ourselves. All placeholders are filled in one pass, so a value
+// that looks like a placeholder, e.g. a recipe called "{description}",
+// shows up as it is. Braces that aren't a placeholder we set, like in CSS
+// and JavaScript, are left alone.
+
+use crate::{assets, escape_html};
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+pub struct Template {
+    html: String,
+    values: HashMap<String, String>,
+}
+
+impl Template {
+    pub fn new(html: &str) -> Template {
+        Template {
+            html: html.to_string(),
+            values: HashMap::new(),
+        }
+    }
+
+    // A part of a page, e.g. the body of a recipe.
+    pub fn load(name: &str) -> Template {
+        Template::new(&assets::text(name))
+    }
+
+    // A whole page: the file inside the common page layout.
+    pub fn page(name: &str) -> Template {
+        let page = assets::text("page.html");
+        Template::new(&page.replacen("{body}", &assets::text(name), 1))
+    }
+
+    pub fn set(&mut self, key: &str, value: impl Display) {
+        self.values
+            .insert(key.to_string(), escape_html(&value.to_string()));
+    }
+
+    pub fn set_html(&mut self, key: &str, html: impl Into<String>) {
+        self.values.insert(key.to_string(), html.into());
+    }
+
+    pub fn render(&self) -> String {
+        static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+        let re = PLACEHOLDER.get_or_init(|| Regex::new(r"\{([a-z_]+)\}").unwrap());
+        re.replace_all(&self.html, |caps: &Captures| {
+            match self.values.get(&caps[1]) {
+                Some(value) => value.clone(),
+                None => caps[0].to_string(),
+            }
+        })
+        .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_values() {
+        let mut template = Template::new("<h1 title=\"{name}\">{name}</h1>{body}");
+        template.set("name", "Mac & \"cheese\" <script>");
+        template.set_html("body", "<p>Bake</p>");
+        assert_eq!(
+            template.render(),
+            "<h1 title=\"Mac &amp; &quot;cheese&quot; &lt;script&gt;\">\
+             Mac &amp; &quot;cheese&quot; &lt;script&gt;</h1><p>Bake</p>"
+        );
+    }
+
+    #[test]
+    fn fills_placeholders_once() {
+        let mut template = Template::new("{name}: {description}");
+        template.set("name", "{description}");
+        template.set("description", 12);
+        assert_eq!(template.render(), "{description}: 12");
+    }
+
+    #[test]
+    fn leaves_other_braces() {
+        let mut template = Template::new("a { color: red; } if (x) {return} {id}");
+        template.set("id", 3);
+        assert_eq!(template.render(), "a { color: red; } if (x) {return} 3");
+    }
+}
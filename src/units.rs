@@ -0,0 +1,398 @@
+// Quantities and units of recipe ingredients: parsing what people type,
+// converting between metric and US measures and rounding the result to
+// something that can actually be measured in a kitchen.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum UnitSystem {
+    Metric,
+    Us,
+}
+
+impl UnitSystem {
+    pub fn parse(text: &str) -> Option<UnitSystem> {
+        match text.trim().to_lowercase().as_str() {
+            "metric" => Some(UnitSystem::Metric),
+            "us" => Some(UnitSystem::Us),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Dimension {
+    Mass,
+    Volume,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Unit {
+    Gram,
+    Kilogram,
+    Ounce,
+    Pound,
+    Millilitre,
+    Litre,
+    Teaspoon,
+    Tablespoon,
+    FluidOunce,
+    Cup,
+}
+
+// Every known unit with its dimension, size in grams or millilitres and
+// the spellings we recognise. The first spelling is the one we print.
+const UNITS: &[(Unit, Dimension, f64, &[&str])] = &[
+    (
+        Unit::Gram,
+        Dimension::Mass,
+        1.0,
+        &["g", "gram", "grams", "gr"],
+    ),
+    (
+        Unit::Kilogram,
+        Dimension::Mass,
+        1000.0,
+        &["kg", "kilogram", "kilograms", "kilo", "kilos"],
+    ),
+    (
+        Unit::Ounce,
+        Dimension::Mass,
+        28.349_523,
+        &["oz", "ounce", "ounces"],
+    ),
+    (
+        Unit::Pound,
+        Dimension::Mass,
+        453.592_37,
+        &["lb", "lbs", "pound", "pounds"],
+    ),
+    (
+        Unit::Millilitre,
+        Dimension::Volume,
+        1.0,
+        &[
+            "ml",
+            "millilitre",
+            "millilitres",
+            "milliliter",
+            "milliliters",
+        ],
+    ),
+    (
+        Unit::Litre,
+        Dimension::Volume,
+        1000.0,
+        &["l", "litre", "litres", "liter", "liters"],
+    ),
+    (
+        Unit::Teaspoon,
+        Dimension::Volume,
+        4.928_922,
+        &["tsp", "teaspoon", "teaspoons", "t"],
+    ),
+    (
+        Unit::Tablespoon,
+        Dimension::Volume,
+        14.786_765,
+        &["tbsp", "tablespoon", "tablespoons", "tbs", "T"],
+    ),
+    (
+        Unit::FluidOunce,
+        Dimension::Volume,
+        29.573_53,
+        &["fl oz", "fluid ounce", "fluid ounces", "floz"],
+    ),
+    (
+        Unit::Cup,
+        Dimension::Volume,
+        236.588_236,
+        &["cup", "cups", "c"],
+    ),
+];
+
+impl Unit {
+    fn parse(text: &str) -> Option<Unit> {
+        let text = text.trim().trim_end_matches('.');
+        // "T" and "t" are the only spellings where case matters.
+        if let Some(unit) = UNITS.iter().find(|(_, _, _, names)| names.contains(&text)) {
+            return Some(unit.0);
+        }
+        let lower = text.to_lowercase();
+        UNITS
+            .iter()
+            .find(|(_, _, _, names)| names.contains(&lower.as_str()))
+            .map(|unit| unit.0)
+    }
+
+    fn entry(&self) -> &'static (Unit, Dimension, f64, &'static [&'static str]) {
+        UNITS
+            .iter()
+            .find(|entry| entry.0 == *self)
+            .expect("Every unit to be in the table")
+    }
+
+    fn dimension(&self) -> Dimension {
+        self.entry().1
+    }
+
+    // Size in grams for masses, millilitres for volumes.
+    fn size(&self) -> f64 {
+        self.entry().2
+    }
+
+    fn symbol(&self) -> &'static str {
+        self.entry().3[0]
+    }
+
+    fn is_spoon(&self) -> bool {
+        matches!(self, Unit::Teaspoon | Unit::Tablespoon)
+    }
+}
+
+// Three teaspoons should make exactly one tablespoon.
+const EPSILON: f64 = 1e-6;
+
+// Picks the unit a converted amount is shown in. `base` is the amount in
+// grams or millilitres.
+fn target_unit(base: f64, from: Unit, system: UnitSystem) -> Unit {
+    match (from.dimension(), system) {
+        (Dimension::Mass, UnitSystem::Metric) if base >= 1000.0 => Unit::Kilogram,
+        (Dimension::Mass, UnitSystem::Metric) => Unit::Gram,
+        (Dimension::Mass, UnitSystem::Us) if base >= Unit::Pound.size() => Unit::Pound,
+        (Dimension::Mass, UnitSystem::Us) => Unit::Ounce,
+        // Spoons are used in metric recipes too, so they stay spoons.
+        (Dimension::Volume, UnitSystem::Metric) if from.is_spoon() => {
+            if base >= Unit::Tablespoon.size() - EPSILON {
+                Unit::Tablespoon
+            } else {
+                Unit::Teaspoon
+            }
+        }
+        (Dimension::Volume, UnitSystem::Metric) if base >= 1000.0 => Unit::Litre,
+        (Dimension::Volume, UnitSystem::Metric) => Unit::Millilitre,
+        (Dimension::Volume, UnitSystem::Us) if base < Unit::Tablespoon.size() - EPSILON => {
+            Unit::Teaspoon
+        }
+        (Dimension::Volume, UnitSystem::Us) if base < Unit::Cup.size() / 4.0 => Unit::Tablespoon,
+        (Dimension::Volume, UnitSystem::Us) => Unit::Cup,
+    }
+}
+
+// Rounds to a precision that makes sense for the unit: whole grams, the
+// nearest eighth of a spoon or cup, and so on.
+fn round_for(quantity: f64, unit: Option<Unit>) -> f64 {
+    let step = match unit {
+        Some(Unit::Gram) | Some(Unit::Millilitre) if quantity >= 100.0 => 5.0,
+        Some(Unit::Gram) | Some(Unit::Millilitre) if quantity >= 10.0 => 1.0,
+        Some(Unit::Gram) | Some(Unit::Millilitre) => 0.5,
+        Some(Unit::Kilogram) | Some(Unit::Litre) => 0.05,
+        Some(_) if quantity >= 1.0 => 0.25,
+        Some(_) => 0.125,
+        None if quantity >= 10.0 => 1.0,
+        None => 0.25,
+    };
+    let per_unit = 1.0 / step;
+    let rounded = (quantity * per_unit).round() / per_unit;
+    // Never round something that is there down to nothing.
+    if rounded == 0.0 && quantity > 0.0 {
+        step
+    } else {
+        rounded
+    }
+}
+
+// Converts an amount to the given system. Units we don't know (pieces,
+// pinches, cloves) are returned unchanged.
+pub fn convert(quantity: f64, unit: &str, system: UnitSystem) -> (f64, String) {
+    let Some(from) = Unit::parse(unit) else {
+        return (quantity, unit.to_string());
+    };
+    let base = quantity * from.size();
+    let to = target_unit(base, from, system);
+    (
+        round_for(base / to.size(), Some(to)),
+        to.symbol().to_string(),
+    )
+}
+
+// Multiplies an amount, e.g. when a recipe is scaled to more servings.
+pub fn scale(quantity: f64, unit: Option<&str>, factor: f64) -> f64 {
+    let scaled = quantity * factor;
+    if factor == 1.0 {
+        return scaled;
+    }
+    round_for(scaled, unit.and_then(Unit::parse))
+}
+
+// Converts an amount into grams or millilitres so amounts of the same
+// dimension can be added up. Returns the base unit's symbol with it.
+pub fn to_base(quantity: f64, unit: &str) -> Option<(f64, &'static str)> {
+    let unit = Unit::parse(unit)?;
+    let base = match unit.dimension() {
+        Dimension::Mass => Unit::Gram,
+        Dimension::Volume => Unit::Millilitre,
+    };
+    Some((quantity * unit.size(), base.symbol()))
+}
+
+// The reverse of `to_base`: how much of `unit` that many grams or
+// millilitres are.
+pub fn from_base(base: f64, unit: &str) -> Option<f64> {
+    Unit::parse(unit).map(|unit| base / unit.size())
+}
+
+// Whether `text` is a unit we can convert, e.g. "tbsp" or "cups".
+pub fn is_unit(text: &str) -> bool {
+    Unit::parse(text).is_some()
+}
+
+// Accepts "200", "1.5", "1,5", "1/2" and "1 1/2".
+pub fn parse_quantity(text: &str) -> Option<f64> {
+    let mut total = 0.0;
+    let mut found = false;
+    for part in text.split_whitespace() {
+        let value = match part.split_once('/') {
+            Some((numerator, denominator)) => {
+                let denominator = denominator.parse::<f64>().ok()?;
+                if denominator == 0.0 {
+                    return None;
+                }
+                numerator.parse::<f64>().ok()? / denominator
+            }
+            None => part.replace(',', ".").parse::<f64>().ok()?,
+        };
+        total += value;
+        found = true;
+    }
+    found.then_some(total)
+}
+
+pub fn format_quantity(quantity: f64) -> String {
+    if (quantity - quantity.round()).abs() < 1e-9 {
+        return format!("{}", quantity.round());
+    }
+    let text = format!("{:.3}", quantity);
+    text.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_units_and_aliases() {
+        assert_eq!(Unit::parse("g"), Some(Unit::Gram));
+        assert_eq!(Unit::parse("Grams"), Some(Unit::Gram));
+        assert_eq!(Unit::parse("tbsp."), Some(Unit::Tablespoon));
+        assert_eq!(Unit::parse("T"), Some(Unit::Tablespoon));
+        assert_eq!(Unit::parse("t"), Some(Unit::Teaspoon));
+        assert_eq!(Unit::parse("fl oz"), Some(Unit::FluidOunce));
+        assert_eq!(Unit::parse("clove"), None);
+    }
+
+    #[test]
+    fn converts_mass() {
+        assert_eq!(convert(100.0, "g", UnitSystem::Us), (3.5, "oz".to_string()));
+        assert_eq!(convert(500.0, "g", UnitSystem::Us), (1.0, "lb".to_string()));
+        assert_eq!(
+            convert(8.0, "oz", UnitSystem::Metric),
+            (225.0, "g".to_string())
+        );
+        assert_eq!(
+            convert(2.0, "lb", UnitSystem::Metric),
+            (905.0, "g".to_string())
+        );
+        assert_eq!(
+            convert(3.0, "lb", UnitSystem::Metric),
+            (1.35, "kg".to_string())
+        );
+        assert_eq!(
+            convert(1500.0, "g", UnitSystem::Metric),
+            (1.5, "kg".to_string())
+        );
+    }
+
+    #[test]
+    fn converts_volume() {
+        assert_eq!(
+            convert(1.0, "cup", UnitSystem::Metric),
+            (235.0, "ml".to_string())
+        );
+        assert_eq!(
+            convert(250.0, "ml", UnitSystem::Us),
+            (1.0, "cup".to_string())
+        );
+        assert_eq!(
+            convert(4.0, "cups", UnitSystem::Metric),
+            (945.0, "ml".to_string())
+        );
+        assert_eq!(
+            convert(5.0, "cups", UnitSystem::Metric),
+            (1.2, "l".to_string())
+        );
+        assert_eq!(
+            convert(30.0, "ml", UnitSystem::Us),
+            (2.0, "tbsp".to_string())
+        );
+        assert_eq!(convert(5.0, "ml", UnitSystem::Us), (1.0, "tsp".to_string()));
+    }
+
+    #[test]
+    fn converts_between_spoons() {
+        assert_eq!(
+            convert(3.0, "tsp", UnitSystem::Metric),
+            (1.0, "tbsp".to_string())
+        );
+        assert_eq!(
+            convert(6.0, "tsp", UnitSystem::Us),
+            (2.0, "tbsp".to_string())
+        );
+        assert_eq!(
+            convert(0.5, "tbsp", UnitSystem::Us),
+            (1.5, "tsp".to_string())
+        );
+        assert_eq!(
+            convert(8.0, "tbsp", UnitSystem::Us),
+            (0.5, "cup".to_string())
+        );
+    }
+
+    #[test]
+    fn leaves_unknown_units_alone() {
+        assert_eq!(
+            convert(3.0, "cloves", UnitSystem::Us),
+            (3.0, "cloves".to_string())
+        );
+        assert_eq!(convert(1.0, "", UnitSystem::Metric), (1.0, "".to_string()));
+    }
+
+    #[test]
+    fn scales_and_rounds() {
+        assert_eq!(scale(200.0, Some("g"), 1.5), 300.0);
+        assert_eq!(scale(1.0, Some("tsp"), 1.0 / 3.0), 0.375);
+        assert_eq!(scale(3.0, None, 0.5), 1.5);
+        assert_eq!(scale(1.0, None, 0.1), 0.25);
+        assert_eq!(scale(123.0, Some("g"), 1.0), 123.0);
+    }
+
+    #[test]
+    fn converts_to_and_from_base_units() {
+        assert_eq!(to_base(2.0, "kg"), Some((2000.0, "g")));
+        assert_eq!(to_base(1.0, "l"), Some((1000.0, "ml")));
+        assert_eq!(to_base(1.0, "pinch"), None);
+        assert_eq!(from_base(1500.0, "kg"), Some(1.5));
+        assert_eq!(from_base(1.0, "pinch"), None);
+    }
+
+    #[test]
+    fn parses_and_formats_quantities() {
+        assert_eq!(parse_quantity("200"), Some(200.0));
+        assert_eq!(parse_quantity("1,5"), Some(1.5));
+        assert_eq!(parse_quantity("1 1/2"), Some(1.5));
+        assert_eq!(parse_quantity("1/0"), None);
+        assert_eq!(parse_quantity("some"), None);
+        assert_eq!(parse_quantity(""), None);
+        assert_eq!(format_quantity(200.0), "200");
+        assert_eq!(format_quantity(0.375), "0.375");
+        assert_eq!(format_quantity(1.5), "1.5");
+    }
+}
@@ -0,0 +1,528 @@
+// Accounts and logging in. Browsers log in on /login and get a session
+// cookie; API clients send the same name and password with HTTP basic auth
+// instead. Passwords are stored as salted argon2 hashes. Users are added
+// and their passwords reset from the command line:
+//
+//     recipe-helper user add NAME
+//     recipe-helper user reset NAME
+//
+// both read the password from standard input. Resetting a password also
+// logs the user out everywhere.
+//
+// Every session has a CSRF token. Forms that change something send it back
+// in a hidden `csrf_token` field, and are turned down without it, so another
+// site can't make a logged in browser post them. Pages put it in their forms
+// with `{csrf_token}`, which `serve_html` fills in. Basic auth has no
+// session, so it is only for the API, whose writes need a JSON body or a
+// method that browsers won't send across sites.
+
+use crate::dates::now;
+use crate::errors::{AppError, Result};
+use crate::forms::{self, Form};
+use crate::template::Template;
+use crate::{find_header, get_con, migrations, query_value, serve_html};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::engine::general_purpose;
+use base64::Engine;
+use rusqlite::{named_params, Connection, OptionalExtension};
+use std::io;
+use tiny_http::{Header, Method, Request, Response, ResponseBox};
+
+const SESSION_COOKIE: &str = "session";
+// How long a login lasts.
+const SESSION_SECONDS: i64 = 30 * 24 * 3600;
+
+#[derive(Debug, PartialEq)]
+pub struct User {
+    pub id: usize,
+    pub name: String,
+}
+
+fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("To hash the password")
+        .to_string()
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|parsed| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok()
+    })
+}
+
+// The user with this name, if the password is right.
+fn check_password(con: &Connection, name: &str, password: &str) -> rusqlite::Result<Option<User>> {
+    let Some((user, hash)) = con
+        .query_row(
+            "SELECT id, name, password_hash FROM users WHERE name = :name",
+            named_params! { ":name": name },
+            |row| {
+                Ok((
+                    User {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                    },
+                    row.get::<_, String>(2)?,
+                ))
+            },
+        )
+        .optional()?
+    else {
+        return Ok(None);
+    };
+    Ok(verify_password(password, &hash).then_some(user))
+}
+
+pub fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn create_session(con: &Connection, user: &User) -> rusqlite::Result<String> {
+    con.execute(
+        "DELETE FROM sessions WHERE expires_at <= :now",
+        named_params! { ":now": now() },
+    )?;
+    let token = new_token();
+    con.execute(
+        "INSERT INTO sessions (token, user_id, expires_at, csrf_token)
+         VALUES (:token, :user_id, :expires_at, :csrf_token)",
+        named_params! {
+            ":token": token,
+            ":user_id": user.id,
+            ":expires_at": now() + SESSION_SECONDS,
+            ":csrf_token": new_token(),
+        },
+    )?;
+    Ok(token)
+}
+
+fn cookie_value(cookies: &str, name: &str) -> Option<String> {
+    cookies
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+// The name and password of a `Basic` Authorization header.
+fn basic_credentials(authorization: &str) -> Option<(String, String)> {
+    let encoded = authorization.strip_prefix("Basic ")?;
+    let decoded = general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (name, password) = decoded.split_once(':')?;
+    Some((name.to_string(), password.to_string()))
+}
+
+fn header_value(request: &Request, name: &str) -> Option<String> {
+    find_header(request.headers(), name.to_string()).map(|h| h.value.as_str().to_string())
+}
+
+fn session_cookie(request: &Request) -> Option<String> {
+    header_value(request, "Cookie").and_then(|c| cookie_value(&c, SESSION_COOKIE))
+}
+
+// Who sent the request, by session cookie, or by basic auth for the API.
+// Pages don't take basic auth: their forms rely on the session's CSRF
+// token, and a browser would send the credentials along to every page.
+pub fn authenticate(request: &Request) -> rusqlite::Result<Option<User>> {
+    let con = get_con()?;
+    if let Some(token) = session_cookie(request) {
+        let user = con
+            .query_row(
+                "SELECT u.id, u.name FROM sessions AS s JOIN users AS u ON u.id = s.user_id
+                 WHERE s.token = :token AND s.expires_at > :now",
+                named_params! { ":token": token, ":now": now() },
+                |row| {
+                    Ok(User {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+        if user.is_some() {
+            return Ok(user);
+        }
+    }
+    if !request.url().starts_with("/api/") {
+        return Ok(None);
+    }
+    let Some((name, password)) =
+        header_value(request, "Authorization").and_then(|a| basic_credentials(&a))
+    else {
+        return Ok(None);
+    };
+    check_password(&con, &name, &password)
+}
+
+fn session_csrf_token(con: &Connection, session: &str) -> rusqlite::Result<Option<String>> {
+    let token = con
+        .query_row(
+            "SELECT csrf_token FROM sessions WHERE token = :token AND expires_at > :now",
+            named_params! { ":token": session, ":now": now() },
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(token.flatten())
+}
+
+// The CSRF token of the request's session, if it has one.
+pub fn csrf_token(request: &Request) -> rusqlite::Result<Option<String>> {
+    match session_cookie(request) {
+        Some(session) => session_csrf_token(&*get_con()?, &session),
+        None => Ok(None),
+    }
+}
+
+// The hidden field for forms built in code rather than in a template.
+pub fn csrf_field(request: &Request) -> rusqlite::Result<String> {
+    Ok(format!(
+        "<input type=\"hidden\" name=\"csrf_token\" value=\"{}\" />",
+        csrf_token(request)?.unwrap_or_default()
+    ))
+}
+
+// Compares every byte, so the time taken doesn't tell how much of a guess
+// was right.
+fn csrf_matches(expected: Option<&str>, sent: Option<&str>) -> bool {
+    match (expected, sent) {
+        (Some(expected), Some(sent)) if expected.len() == sent.len() => {
+            expected
+                .bytes()
+                .zip(sent.bytes())
+                .fold(0, |difference, (a, b)| difference | (a ^ b))
+                == 0
+        }
+        _ => false,
+    }
+}
+
+// The fields of a form that changes something, if it came with the
+// session's CSRF token.
+pub fn read_checked_form(request: &mut Request) -> Result<Form> {
+    let param_map = forms::read_form(request)?;
+    let expected = csrf_token(request)?;
+    match csrf_matches(
+        expected.as_deref(),
+        param_map.get("csrf_token").map(String::as_str),
+    ) {
+        true => Ok(param_map),
+        false => Err(AppError::Forbidden(
+            "This form has expired or didn't come from this site. Go back, reload the page and try again."
+                .to_string(),
+        )),
+    }
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field, value).expect("That we didn't put any garbage in the headers")
+}
+
+// 303, so the browser follows with a GET and doesn't remember the redirect.
+fn see_other(destination: &str, cookie: Option<String>) -> Result<ResponseBox> {
+    let mut response = Response::empty(303).with_header(header("Location", destination));
+    if let Some(cookie) = cookie {
+        response.add_header(header("Set-Cookie", &cookie));
+    }
+    Ok(response.boxed())
+}
+
+// Where to go after logging in: only paths on this site.
+fn safe_next(next: &str) -> &str {
+    match next.starts_with('/')
+        && !next.starts_with("//")
+        && !next.contains('\\')
+        && !next.chars().any(char::is_control)
+    {
+        true => next,
+        false => "/",
+    }
+}
+
+// Answers a request from someone who isn't logged in: API clients are
+// asked for basic auth, browsers are sent to the login page.
+pub fn require_login(request: &Request) -> Result<ResponseBox> {
+    if request.url().starts_with("/api/") {
+        return Ok(Response::from_string("Log in with HTTP basic auth")
+            .with_header(header("WWW-Authenticate", "Basic realm=\"Recipe Helper\""))
+            .with_status_code(401)
+            .boxed());
+    }
+    // Only pages can be shown again after logging in, not form posts.
+    let next = match request.method() {
+        Method::Get => request.url().to_string(),
+        _ => "/".to_string(),
+    };
+    let login = format!("/login?next={}", urlencoding::encode(&next));
+    see_other(&login, None)
+}
+
+// Entry point for /login and /logout, which work without being logged in.
+pub fn handle_login(request: &mut Request) -> Result<ResponseBox> {
+    let url = request.url().to_string();
+    let path = url.split('?').next().unwrap_or_default();
+    match (request.method(), path) {
+        (Method::Get, "/login") => {
+            let next = query_value(&url, "next").unwrap_or_default();
+            login_page(request, safe_next(&next), "")
+        }
+        (Method::Post, "/login") => login_post(request),
+        (Method::Post, "/logout") => logout_post(request),
+        _ => see_other("/login", None),
+    }
+}
+
+fn login_page(request: &Request, next: &str, error: &str) -> Result<ResponseBox> {
+    let mut page = Template::page("login.html");
+    page.set("next", next);
+    let error = match error.is_empty() {
+        true => String::new(),
+        false => format!("<div class=\"login-error\">{}</div>", error),
+    };
+    page.set_html("error", error);
+    serve_html(request, page)
+}
+
+fn login_post(request: &mut Request) -> Result<ResponseBox> {
+    let param_map = forms::read_form(request)?;
+    let name = param_map.get("name").map(|n| n.trim()).unwrap_or_default();
+    let password = param_map
+        .get("password")
+        .map(String::as_str)
+        .unwrap_or_default();
+    let next = safe_next(param_map.get("next").map(String::as_str).unwrap_or("/"));
+    let con = get_con()?;
+    match check_password(&con, name, password)? {
+        Some(user) => {
+            let cookie = format!(
+                "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+                SESSION_COOKIE,
+                create_session(&con, &user)?,
+                SESSION_SECONDS
+            );
+            see_other(next, Some(cookie))
+        }
+        None => login_page(request, next, "Wrong name or password."),
+    }
+}
+
+// Logging out of a session that is already gone needs no token.
+fn logout_post(request: &mut Request) -> Result<ResponseBox> {
+    if csrf_token(request)?.is_some() {
+        read_checked_form(request)?;
+    }
+    if let Some(token) = session_cookie(request) {
+        get_con()?.execute(
+            "DELETE FROM sessions WHERE token = :token",
+            named_params! { ":token": token },
+        )?;
+    }
+    let cookie = format!(
+        "{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax",
+        SESSION_COOKIE
+    );
+    see_other("/login", Some(cookie))
+}
+
+// Adds a user or sets a new password. Returns what happened.
+fn set_password(
+    con: &Connection,
+    action: &str,
+    name: &str,
+    password: &str,
+) -> std::result::Result<String, String> {
+    if name.is_empty() || password.is_empty() {
+        return Err("The name and password can't be empty".to_string());
+    }
+    let hash = hash_password(password);
+    match action {
+        "add" => {
+            let added = con
+                .execute(
+                    "INSERT OR IGNORE INTO users (name, password_hash) VALUES (:name, :hash)",
+                    named_params! { ":name": name, ":hash": hash },
+                )
+                .map_err(|e| e.to_string())?;
+            match added {
+                0 => Err(format!("{} already exists", name)),
+                _ => Ok(format!("added {}", name)),
+            }
+        }
+        _ => {
+            let updated = con
+                .execute(
+                    "UPDATE users SET password_hash = :hash WHERE name = :name",
+                    named_params! { ":name": name, ":hash": hash },
+                )
+                .map_err(|e| e.to_string())?;
+            if updated == 0 {
+                return Err(format!("there is no user {}", name));
+            }
+            con.execute(
+                "DELETE FROM sessions
+                 WHERE user_id = (SELECT id FROM users WHERE name = :name)",
+                named_params! { ":name": name },
+            )
+            .map_err(|e| e.to_string())?;
+            Ok(format!("reset the password of {}", name))
+        }
+    }
+}
+
+pub fn user_command(args: &[String]) {
+    let (Some(action @ ("add" | "reset")), Some(name), None) =
+        (args.first().map(String::as_str), args.get(1), args.get(2))
+    else {
+        eprintln!("usage: recipe-helper user add|reset NAME");
+        std::process::exit(2);
+    };
+    let mut con = get_con().unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    });
+    if let Err(e) = migrations::migrate(&mut con) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+    eprint!("Password for {}: ", name);
+    let mut password = String::new();
+    if let Err(e) = io::stdin().read_line(&mut password) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+    match set_password(&con, action, name.trim(), password) {
+        Ok(message) => println!("{}", message),
+        Err(message) => {
+            eprintln!("error: {}", message);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database() -> Connection {
+        let mut con = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut con).unwrap();
+        con
+    }
+
+    #[test]
+    fn checks_passwords() {
+        let con = database();
+        assert!(set_password(&con, "add", "ada", "pa+ss word").is_ok());
+        assert!(set_password(&con, "add", "ada", "other").is_err());
+        let hash: String = con
+            .query_row("SELECT password_hash FROM users", [], |row| row.get(0))
+            .unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(!hash.contains("pa+ss word"));
+
+        let user = check_password(&con, "ada", "pa+ss word").unwrap().unwrap();
+        assert_eq!(user.name, "ada");
+        assert_eq!(check_password(&con, "ada", "pa ss word").unwrap(), None);
+        assert_eq!(check_password(&con, "bob", "pa+ss word").unwrap(), None);
+
+        let token = create_session(&con, &user).unwrap();
+        let csrf_token = session_csrf_token(&con, &token).unwrap().unwrap();
+        assert_ne!(csrf_token, token);
+        assert_eq!(csrf_token.len(), 64);
+        assert!(set_password(&con, "reset", "ada", "new").is_ok());
+        assert_eq!(session_csrf_token(&con, &token).unwrap(), None);
+        assert!(check_password(&con, "ada", "new").unwrap().is_some());
+        let sessions: usize = con
+            .query_row(
+                "SELECT count(*) FROM sessions WHERE token = :token",
+                named_params! { ":token": token },
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(sessions, 0);
+        assert!(set_password(&con, "reset", "bob", "new").is_err());
+    }
+
+    #[test]
+    fn reads_credentials() {
+        assert_eq!(
+            cookie_value("theme=dark; session=abc; x=1", "session"),
+            Some("abc".to_string())
+        );
+        assert_eq!(cookie_value("sessions=abc", "session"), None);
+        // "ada:se:cret"
+        assert_eq!(
+            basic_credentials("Basic YWRhOnNlOmNyZXQ="),
+            Some(("ada".to_string(), "se:cret".to_string()))
+        );
+        assert_eq!(basic_credentials("Bearer YWRhOnNlOmNyZXQ="), None);
+    }
+
+    #[test]
+    fn takes_basic_auth_only_for_the_api() {
+        let con = get_con().unwrap();
+        set_password(&con, "add", "users-basic", "se:cret").unwrap();
+        let user = check_password(&con, "users-basic", "se:cret")
+            .unwrap()
+            .unwrap();
+        let basic = format!(
+            "Basic {}",
+            general_purpose::STANDARD.encode("users-basic:se:cret")
+        );
+        let session = format!(
+            "{}={}",
+            SESSION_COOKIE,
+            create_session(&con, &user).unwrap()
+        );
+        let request = |path: &str, name: &str, value: &str| -> Request {
+            tiny_http::TestRequest::new()
+                .with_path(path)
+                .with_header(header(name, value))
+                .into()
+        };
+        let who = |request: Request| authenticate(&request).unwrap().map(|u| u.id);
+
+        assert_eq!(
+            who(request("/api/v1/recipes", "Authorization", &basic)),
+            Some(user.id)
+        );
+        assert_eq!(who(request("/", "Authorization", &basic)), None);
+        assert_eq!(
+            who(request("/recipe/1/cook", "Authorization", &basic)),
+            None
+        );
+        assert_eq!(who(request("/", "Cookie", &session)), Some(user.id));
+        assert_eq!(
+            who(request("/api/v1/recipes", "Cookie", &session)),
+            Some(user.id)
+        );
+    }
+
+    #[test]
+    fn compares_csrf_tokens() {
+        assert!(csrf_matches(Some("abc123"), Some("abc123")));
+        assert!(!csrf_matches(Some("abc123"), Some("abc124")));
+        assert!(!csrf_matches(Some("abc123"), Some("abc")));
+        assert!(!csrf_matches(Some("abc123"), None));
+        assert!(!csrf_matches(None, Some("")));
+        assert!(!csrf_matches(None, None));
+    }
+
+    #[test]
+    fn only_redirects_to_this_site() {
+        assert_eq!(safe_next("/recipe/1?servings=2"), "/recipe/1?servings=2");
+        assert_eq!(safe_next("https://example.com"), "/");
+        assert_eq!(safe_next("//example.com"), "/");
+        assert_eq!(safe_next("/\\example.com"), "/");
+        assert_eq!(safe_next("/a\r\nSet-Cookie: x"), "/");
+    }
+}